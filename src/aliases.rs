@@ -0,0 +1,78 @@
+use crate::kv::{KVStore, RocksDB};
+use crate::validation::Validation;
+
+use actix_web::{
+    web::{Data, Json, Path},
+    HttpResponse,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+/// Alias as stored under `_alias/{name}`.
+#[derive(Serialize, Deserialize)]
+pub struct Alias {
+    key: String,
+}
+
+fn alias_key(name: &str) -> String {
+    format!("_alias/{name}")
+}
+
+fn alias_json(name: &str, alias: &Alias) -> String {
+    json!({ "name": name, "key": alias.key }).to_string()
+}
+
+/// The key `name` points to, if it is an alias.
+pub fn resolve(db: &RocksDB, name: &str) -> Option<String> {
+    db.find(&alias_key(name))
+        .and_then(|v| serde_json::from_str::<Alias>(&v).ok())
+        .map(|alias| alias.key)
+}
+
+/// Points the alias at a key, replacing its previous target in one write.
+pub async fn put(
+    name: Path<String>,
+    db: Data<RocksDB>,
+    validation: Data<Validation>,
+    body: Json<Alias>,
+) -> HttpResponse {
+    let name = name.into_inner();
+    let alias = body.into_inner();
+    if let Err(res) = validation
+        .check_key(&name)
+        .and_then(|_| validation.check_key(&alias.key))
+    {
+        return res;
+    }
+
+    if db.save(&alias_key(&name), &serde_json::to_string(&alias).unwrap()) {
+        HttpResponse::Ok()
+            .content_type("application/json")
+            .body(alias_json(&name, &alias))
+    } else {
+        HttpResponse::InternalServerError()
+            .content_type("application/json")
+            .finish()
+    }
+}
+
+pub async fn get(name: Path<String>, db: Data<RocksDB>) -> HttpResponse {
+    let name = name.into_inner();
+    match resolve(&db, &name) {
+        Some(key) => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(alias_json(&name, &Alias { key })),
+        None => HttpResponse::NotFound()
+            .content_type("application/json")
+            .finish(),
+    }
+}
+
+pub async fn delete(name: Path<String>, db: Data<RocksDB>) -> HttpResponse {
+    match db.delete(&alias_key(&name)) {
+        true => HttpResponse::Ok().content_type("application/json").finish(),
+        false => HttpResponse::InternalServerError()
+            .content_type("application/json")
+            .finish(),
+    }
+}
@@ -0,0 +1,8 @@
+use actix_web::HttpRequest;
+
+pub fn is_admin(req: &HttpRequest, token: &str) -> bool {
+    req.headers()
+        .get("Authorization")
+        .and_then(|hv| hv.to_str().ok())
+        == Some(token)
+}
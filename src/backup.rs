@@ -0,0 +1,485 @@
+use crate::auth;
+use crate::clock;
+use crate::kv::RocksDB;
+
+use actix_files::NamedFile;
+use actix_web::{
+    web::{block, Data, Path as UrlPath, Query},
+    HttpRequest, HttpResponse,
+};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::{
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    None,
+    Zstd,
+}
+
+impl Compression {
+    fn extension(&self) -> &'static str {
+        match self {
+            Compression::None => "tar",
+            Compression::Zstd => "tar.zst",
+        }
+    }
+}
+
+/// Stored as `<id>.json` next to the archive it describes.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BackupRecord {
+    pub id: String,
+    pub file: String,
+    pub size: u64,
+    pub compression: Compression,
+    /// Hex encoded SHA-256 of the archive. Missing on records written before
+    /// checksums were introduced.
+    #[serde(default)]
+    pub sha256: Option<String>,
+}
+
+/// A file inside a checkpoint directory.
+#[derive(Debug, Serialize)]
+pub struct CheckpointFile {
+    pub name: String,
+    pub size: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CheckpointRecord {
+    pub id: String,
+    /// Absolute path of the checkpoint directory
+    pub path: PathBuf,
+    pub size: u64,
+    pub files: Vec<CheckpointFile>,
+}
+
+#[derive(Deserialize)]
+pub struct VerifyParams {
+    id: String,
+}
+
+#[derive(Clone)]
+pub struct BackupConfig {
+    path: PathBuf,
+    checkpoint_path: PathBuf,
+    compression: Compression,
+    rate_limit: u64,
+}
+
+impl BackupConfig {
+    pub fn from_env() -> Self {
+        let path = std::env::var("BACKUP_PATH").unwrap_or("./backups".to_string());
+        let checkpoint_path =
+            std::env::var("CHECKPOINT_PATH").unwrap_or("./checkpoints".to_string());
+        let compression = match std::env::var("BACKUP_COMPRESSION")
+            .unwrap_or("zstd".to_string())
+            .as_str()
+        {
+            "none" => Compression::None,
+            "zstd" => Compression::Zstd,
+            other => panic!("Unsupported BACKUP_COMPRESSION: {other}"),
+        };
+        // Bytes per second, 0 disables throttling
+        let rate_limit = std::env::var("BACKUP_RATE_LIMIT")
+            .unwrap_or("0".to_string())
+            .parse::<u64>()
+            .unwrap();
+
+        BackupConfig {
+            path: PathBuf::from(path),
+            checkpoint_path: PathBuf::from(checkpoint_path),
+            compression,
+            rate_limit,
+        }
+    }
+
+    /// Checkpoints the whole database and packs it into a single (optionally
+    /// compressed) tarball inside the backup directory. The intermediate
+    /// checkpoint is removed afterwards.
+    fn archive(&self, db: &RocksDB, id: &str) -> io::Result<BackupRecord> {
+        fs::create_dir_all(&self.path)?;
+        let checkpoint_dir = self.path.join(format!("{id}.checkpoint"));
+        let file = format!("{id}.{}", self.compression.extension());
+        let archive_path = self.path.join(&file);
+
+        db.checkpoint(&checkpoint_dir)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+        let result = File::create(&archive_path)
+            .and_then(|out| write_archive(out, &checkpoint_dir, self.compression, self.rate_limit));
+        fs::remove_dir_all(&checkpoint_dir)?;
+        if let Err(e) = result {
+            let _ = fs::remove_file(&archive_path);
+            return Err(e);
+        }
+
+        let record = BackupRecord {
+            id: id.to_string(),
+            file,
+            size: fs::metadata(&archive_path)?.len(),
+            compression: self.compression,
+            sha256: Some(sha256(&archive_path)?),
+        };
+        fs::write(
+            self.path.join(format!("{id}.json")),
+            serde_json::to_vec(&record)?,
+        )?;
+        Ok(record)
+    }
+
+    /// Creates a checkpoint at `<CHECKPOINT_PATH>/<id>` and leaves it there
+    /// for external tooling to pick up. SST files are hard links into the live
+    /// database, so it takes little space until they're compacted away.
+    fn checkpoint(&self, db: &RocksDB, id: &str) -> io::Result<CheckpointRecord> {
+        fs::create_dir_all(&self.checkpoint_path)?;
+        let dir = self.checkpoint_path.join(id);
+        db.checkpoint(&dir)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            files.push(CheckpointFile {
+                name: entry.file_name().to_string_lossy().into_owned(),
+                size: entry.metadata()?.len(),
+            });
+        }
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(CheckpointRecord {
+            id: id.to_string(),
+            path: fs::canonicalize(&dir)?,
+            size: files.iter().map(|f| f.size).sum(),
+            files,
+        })
+    }
+
+    fn load_record(&self, id: &str) -> io::Result<BackupRecord> {
+        // Anything but a generated id could escape the backup dir
+        if !is_id(id) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "unknown backup"));
+        }
+        let raw = fs::read(self.path.join(format!("{id}.json")))?;
+        Ok(serde_json::from_slice(&raw)?)
+    }
+
+    /// Checks the archive against its record and makes sure it can be read back
+    /// as a tarball. Returns the list of problems found.
+    fn verify(&self, record: &BackupRecord) -> io::Result<Vec<String>> {
+        let archive_path = self.path.join(&record.file);
+        let mut errors = Vec::new();
+
+        let size = fs::metadata(&archive_path)?.len();
+        if size != record.size {
+            errors.push(format!(
+                "size mismatch: expected {}, found {}",
+                record.size, size
+            ));
+        }
+        match &record.sha256 {
+            Some(expected) => {
+                let actual = sha256(&archive_path)?;
+                if &actual != expected {
+                    errors.push(format!(
+                        "checksum mismatch: expected {expected}, found {actual}"
+                    ));
+                }
+            }
+            None => errors.push("no checksum recorded for this backup".to_string()),
+        }
+        if errors.is_empty() {
+            if let Err(e) = read_archive(&archive_path, record.compression) {
+                errors.push(format!("archive is unreadable: {e}"));
+            }
+        }
+        Ok(errors)
+    }
+}
+
+/// A new backup or checkpoint id: the time in milliseconds, so ids sort by
+/// age, and a random suffix, so two taken in the same millisecond don't
+/// overwrite each other. Lowercase, for case insensitive filesystems.
+fn new_id() -> String {
+    let suffix: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(8)
+        .map(|c| char::from(c).to_ascii_lowercase())
+        .collect();
+    format!("{}-{suffix}", clock::now())
+}
+
+/// Whether `id` looks like one `new_id` made, or a plain timestamp as older
+/// versions used.
+fn is_id(id: &str) -> bool {
+    let (time, suffix) = id.split_once('-').unwrap_or((id, "0"));
+    !time.is_empty()
+        && time.chars().all(|c| c.is_ascii_digit())
+        && !suffix.is_empty()
+        && suffix.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+fn sha256(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn read_archive(path: &Path, compression: Compression) -> io::Result<()> {
+    let file = File::open(path)?;
+    match compression {
+        Compression::None => read_tar(file),
+        Compression::Zstd => read_tar(zstd::Decoder::new(file)?),
+    }
+}
+
+fn read_tar<R: Read>(input: R) -> io::Result<()> {
+    for entry in tar::Archive::new(input).entries()? {
+        io::copy(&mut entry?, &mut io::sink())?;
+    }
+    Ok(())
+}
+
+fn write_archive(
+    out: File,
+    dir: &Path,
+    compression: Compression,
+    rate_limit: u64,
+) -> io::Result<()> {
+    let out = match compression {
+        Compression::None => tar_dir(Throttle::new(out, rate_limit), dir)?.inner,
+        Compression::Zstd => {
+            let encoder = zstd::Encoder::new(out, 0)?;
+            tar_dir(Throttle::new(encoder, rate_limit), dir)?
+                .inner
+                .finish()?
+        }
+    };
+    out.sync_all()
+}
+
+fn tar_dir<W: Write>(out: W, dir: &Path) -> io::Result<W> {
+    let mut builder = tar::Builder::new(out);
+    builder.append_dir_all(".", dir)?;
+    builder.into_inner()
+}
+
+/// Writer that sleeps whenever more than `bytes_per_sec` have gone through it
+/// on average, so backups don't starve foreground writes of disk bandwidth.
+struct Throttle<W> {
+    inner: W,
+    bytes_per_sec: u64,
+    written: u64,
+    started: Instant,
+}
+
+impl<W> Throttle<W> {
+    fn new(inner: W, bytes_per_sec: u64) -> Self {
+        Throttle {
+            inner,
+            bytes_per_sec,
+            written: 0,
+            started: Instant::now(),
+        }
+    }
+}
+
+impl<W: Write> Write for Throttle<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if self.bytes_per_sec > 0 {
+            self.written += n as u64;
+            let due = Duration::from_secs_f64(self.written as f64 / self.bytes_per_sec as f64);
+            if let Some(ahead) = due.checked_sub(self.started.elapsed()) {
+                thread::sleep(ahead);
+            }
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub async fn create(
+    db: Data<RocksDB>,
+    token: Data<String>,
+    config: Data<BackupConfig>,
+    req: HttpRequest,
+) -> HttpResponse {
+    if !auth::is_admin(&req, &token) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let id = new_id();
+
+    let backup_id = id.clone();
+    match block(move || config.archive(&db, &backup_id)).await {
+        Ok(Ok(record)) => {
+            log::info!("Created backup {} ({} bytes)", record.file, record.size);
+            HttpResponse::Ok()
+                .content_type("application/json")
+                .body(json!(record).to_string())
+        }
+        Ok(Err(e)) => {
+            log::error!("Backup {} failed: {}", id, e);
+            HttpResponse::InternalServerError()
+                .content_type("application/json")
+                .body(json!({ "status": 500, "msg": format!("Backup failed: {}", e) }).to_string())
+        }
+        Err(_) => HttpResponse::InternalServerError()
+            .content_type("application/json")
+            .finish(),
+    }
+}
+
+pub async fn checkpoint(
+    db: Data<RocksDB>,
+    token: Data<String>,
+    config: Data<BackupConfig>,
+    req: HttpRequest,
+) -> HttpResponse {
+    if !auth::is_admin(&req, &token) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let id = new_id();
+
+    let checkpoint_id = id.clone();
+    match block(move || config.checkpoint(&db, &checkpoint_id)).await {
+        Ok(Ok(record)) => {
+            log::info!("Created checkpoint at {}", record.path.display());
+            HttpResponse::Ok()
+                .content_type("application/json")
+                .body(json!(record).to_string())
+        }
+        Ok(Err(e)) => {
+            log::error!("Checkpoint {} failed: {}", id, e);
+            HttpResponse::InternalServerError()
+                .content_type("application/json")
+                .body(
+                    json!({ "status": 500, "msg": format!("Checkpoint failed: {}", e) })
+                        .to_string(),
+                )
+        }
+        Err(_) => HttpResponse::InternalServerError()
+            .content_type("application/json")
+            .finish(),
+    }
+}
+
+pub async fn verify(
+    token: Data<String>,
+    config: Data<BackupConfig>,
+    params: Query<VerifyParams>,
+    req: HttpRequest,
+) -> HttpResponse {
+    if !auth::is_admin(&req, &token) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let id = params.into_inner().id;
+    let result = block(move || {
+        let record = config.load_record(&id)?;
+        let errors = config.verify(&record)?;
+        Ok::<_, io::Error>((record, errors))
+    })
+    .await;
+
+    match result {
+        Ok(Ok((record, errors))) => {
+            let body = json!({
+                "id": record.id,
+                "valid": errors.is_empty(),
+                "errors": errors,
+            })
+            .to_string();
+            if errors.is_empty() {
+                HttpResponse::Ok()
+            } else {
+                HttpResponse::UnprocessableEntity()
+            }
+            .content_type("application/json")
+            .body(body)
+        }
+        Ok(Err(e)) if e.kind() == io::ErrorKind::NotFound => HttpResponse::NotFound()
+            .content_type("application/json")
+            .body(json!({ "status": 404, "msg": "Backup not found" }).to_string()),
+        Ok(Err(e)) => HttpResponse::InternalServerError()
+            .content_type("application/json")
+            .body(
+                json!({ "status": 500, "msg": format!("Verification failed: {}", e) }).to_string(),
+            ),
+        Err(_) => HttpResponse::InternalServerError()
+            .content_type("application/json")
+            .finish(),
+    }
+}
+
+/// Serves the archive itself. NamedFile takes care of `Range`, `If-Range` and
+/// ETag handling, so interrupted downloads of large backups can be resumed.
+pub async fn download(
+    id: UrlPath<String>,
+    token: Data<String>,
+    config: Data<BackupConfig>,
+    req: HttpRequest,
+) -> HttpResponse {
+    if !auth::is_admin(&req, &token) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let file = config
+        .load_record(&id)
+        .and_then(|record| NamedFile::open(config.path.join(record.file)));
+    match file {
+        Ok(file) => file.into_response(&req),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => HttpResponse::NotFound()
+            .content_type("application/json")
+            .body(json!({ "status": 404, "msg": "Backup not found" }).to_string()),
+        Err(e) => HttpResponse::InternalServerError()
+            .content_type("application/json")
+            .body(
+                json!({ "status": 500, "msg": format!("Failed to open backup: {}", e) })
+                    .to_string(),
+            ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_taken_together_differ() {
+        let (a, b) = (new_id(), new_id());
+        assert_ne!(a, b);
+        assert!(is_id(&a) && is_id(&b));
+    }
+
+    #[test]
+    fn only_generated_ids_are_accepted() {
+        assert!(is_id("1729000000000"));
+        assert!(is_id("1729000000000-k3x9q2ab"));
+        for id in [
+            "",
+            "-",
+            "1729000000000-",
+            "-k3x9q2ab",
+            "../1729000000000",
+            "1-a/b",
+            "1-a-b",
+        ] {
+            assert!(!is_id(id), "{id}");
+        }
+    }
+}
@@ -0,0 +1,26 @@
+use actix_web::HttpResponse;
+use serde_json::json;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Longest ttl locks and leases accept, one year in milliseconds. Keeps
+/// `now() + ttl` far from overflowing.
+pub const MAX_TTL: u64 = 365 * 24 * 60 * 60 * 1000;
+
+/// Milliseconds since the Unix epoch, the unit stored expiry times use.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+/// Rejects a ttl over [`MAX_TTL`] with 400.
+pub fn check_ttl(ttl: u64) -> Result<(), HttpResponse> {
+    if ttl > MAX_TTL {
+        let msg = format!("ttl must be at most {MAX_TTL} milliseconds");
+        return Err(HttpResponse::BadRequest()
+            .content_type("application/json")
+            .body(json!({ "status": 400, "msg": msg }).to_string()));
+    }
+    Ok(())
+}
@@ -0,0 +1,67 @@
+use crate::kv::RocksDB;
+
+use std::{
+    sync::mpsc::{self, RecvTimeoutError},
+    time::{Duration, Instant},
+};
+use tokio::sync::oneshot;
+
+/// Most writes committed in a single batch.
+const MAX_BATCH: usize = 1000;
+
+struct Pending {
+    key: String,
+    value: String,
+    done: oneshot::Sender<bool>,
+}
+
+/// Groups writes arriving within a short window into one RocksDB write batch,
+/// trading a few milliseconds of latency for much higher small-write
+/// throughput.
+pub struct WriteCoalescer {
+    tx: mpsc::Sender<Pending>,
+}
+
+impl WriteCoalescer {
+    /// Starts the thread committing batches every `window`.
+    pub fn spawn(db: RocksDB, window: Duration) -> Self {
+        let (tx, rx) = mpsc::channel::<Pending>();
+        std::thread::spawn(move || {
+            while let Ok(first) = rx.recv() {
+                let deadline = Instant::now() + window;
+                let mut batch = vec![first];
+                while batch.len() < MAX_BATCH {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    match rx.recv_timeout(left) {
+                        Ok(pending) => batch.push(pending),
+                        Err(RecvTimeoutError::Timeout | RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+
+                let result =
+                    db.write_batch(batch.iter().map(|p| (p.key.as_str(), p.value.as_str())));
+                if let Err(e) = &result {
+                    log::error!("Error committing batch of {} writes: {}", batch.len(), e);
+                }
+                for pending in batch {
+                    let _ = pending.done.send(result.is_ok());
+                }
+            }
+        });
+        WriteCoalescer { tx }
+    }
+
+    /// Queues the write and resolves once its batch is committed.
+    pub async fn save(&self, key: &str, value: String) -> bool {
+        let (done, committed) = oneshot::channel();
+        let pending = Pending {
+            key: key.to_string(),
+            value,
+            done,
+        };
+        if self.tx.send(pending).is_err() {
+            return false;
+        }
+        committed.await.unwrap_or(false)
+    }
+}
@@ -0,0 +1,49 @@
+use actix_web::{http::header, HttpRequest, HttpResponse, HttpResponseBuilder};
+use serde_json::{json, Value};
+
+const CBOR: &str = "application/cbor";
+
+fn header_has(req: &HttpRequest, name: header::HeaderName, mime: &str) -> bool {
+    req.headers()
+        .get(name)
+        .and_then(|hv| hv.to_str().ok())
+        .map_or(false, |hv| hv.contains(mime))
+}
+
+/// Parses a request body, reading CBOR when the client sent
+/// `Content-Type: application/cbor` and JSON otherwise. Values are always
+/// stored as JSON.
+pub fn decode(req: &HttpRequest, body: &[u8]) -> Result<Value, HttpResponse> {
+    let (parsed, format) = if header_has(req, header::CONTENT_TYPE, CBOR) {
+        (ciborium::from_reader(body).ok(), "CBOR")
+    } else {
+        (serde_json::from_slice(body).ok(), "JSON")
+    };
+    parsed.ok_or_else(|| {
+        HttpResponse::BadRequest()
+            .content_type("application/json")
+            .body(
+                json!({ "status": 400, "msg": format!("Parsing failed. value is not in {} Format", format) })
+                    .to_string(),
+            )
+    })
+}
+
+pub fn accepts_cbor(req: &HttpRequest) -> bool {
+    header_has(req, header::ACCEPT, CBOR)
+}
+
+/// Finishes `res` with `value` encoded as CBOR if the client sent
+/// `Accept: application/cbor`, as JSON otherwise.
+pub fn respond(req: &HttpRequest, mut res: HttpResponseBuilder, value: &Value) -> HttpResponse {
+    if accepts_cbor(req) {
+        let mut buf = Vec::new();
+        if ciborium::into_writer(value, &mut buf).is_ok() {
+            return res.content_type(CBOR).body(buf);
+        }
+        return HttpResponse::InternalServerError()
+            .content_type("application/json")
+            .finish();
+    }
+    res.content_type("application/json").body(value.to_string())
+}
@@ -0,0 +1,57 @@
+use crate::auth;
+use crate::clock;
+
+use actix_web::{web::Data, HttpRequest, HttpResponse};
+use bytes::Bytes;
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use tokio::sync::broadcast;
+use tokio_stream::{wrappers::BroadcastStream, StreamExt};
+
+/// Events a slow subscriber can fall behind by before it starts missing some.
+const BUFFER: usize = 256;
+
+/// Fans out operational events (write stalls and the like) to every admin
+/// subscribed to the event stream. Nothing is kept for later subscribers.
+pub struct Events {
+    tx: broadcast::Sender<String>,
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Events {
+            tx: broadcast::channel(BUFFER).0,
+        }
+    }
+}
+
+impl Events {
+    pub fn emit(&self, kind: &str, data: Value) {
+        let at = clock::now();
+        let event = format!(
+            "event: {kind}\ndata: {}\n\n",
+            json!({ "kind": kind, "at": at, "data": data })
+        );
+        // Failing only means nobody is listening
+        let _ = self.tx.send(event);
+    }
+}
+
+/// Streams events as server-sent events until the client disconnects.
+pub async fn stream(events: Data<Events>, token: Data<String>, req: HttpRequest) -> HttpResponse {
+    if !auth::is_admin(&req, &token) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let events = BroadcastStream::new(events.tx.subscribe()).map(|event| match event {
+        Ok(event) => event,
+        Err(e) => format!(": {e}\n\n"),
+    });
+    // Opening with a comment gets the response headers out right away
+    let stream = tokio_stream::once(": connected\n\n".to_string())
+        .chain(events)
+        .map(|event| Ok::<_, Infallible>(Bytes::from(event)));
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(stream)
+}
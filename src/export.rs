@@ -0,0 +1,683 @@
+use crate::auth;
+use crate::kv::RocksDB;
+use crate::validation::Validation;
+
+use actix_web::{
+    rt::task,
+    web::{Data, Json, Query},
+    HttpRequest, HttpResponse,
+};
+use bytes::Bytes;
+use flate2::{write::GzEncoder, Compression};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::io::{self, Write};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Ndjson,
+    Csv,
+    #[cfg(feature = "parquet")]
+    Parquet,
+}
+
+#[derive(Deserialize)]
+pub struct ExportParams {
+    #[serde(default)]
+    format: Format,
+    #[serde(default)]
+    gzip: bool,
+    /// Comma separated, dot delimited JSON paths to use as CSV columns
+    columns: Option<String>,
+    /// Comma separated `path:type` Parquet columns, instead of inferring them
+    #[cfg(feature = "parquet")]
+    schema: Option<String>,
+}
+
+#[cfg(feature = "parquet")]
+impl ExportParams {
+    fn parquet_columns(&self) -> Result<Option<Vec<Column>>, String> {
+        self.schema.as_deref().map(Column::parse_all).transpose()
+    }
+}
+
+type Entry = io::Result<(Box<[u8]>, Box<[u8]>)>;
+
+/// Which entries to export. Empty means all of them. Internal state under
+/// reserved prefixes is never exported.
+#[derive(Default, Deserialize)]
+pub struct Selection {
+    /// Exact keys to export, in this order. Missing ones are skipped.
+    keys: Option<Vec<String>>,
+    prefix: Option<String>,
+    /// Dot delimited JSON paths, as used for CSV columns, mapped to the value
+    /// they must hold for the entry to be exported
+    #[serde(default)]
+    filter: Map<String, Value>,
+}
+
+impl Selection {
+    fn entries<'a>(
+        &'a self,
+        db: &'a RocksDB,
+        validation: &'a Validation,
+    ) -> Box<dyn Iterator<Item = Entry> + 'a> {
+        let to_io = |e| io::Error::new(io::ErrorKind::Other, e);
+        let entries: Box<dyn Iterator<Item = Entry>> = match (&self.keys, &self.prefix) {
+            (Some(keys), _) => Box::new(keys.iter().filter_map(move |key| {
+                db.find_raw(key)
+                    .map(|value| Ok((key.as_bytes().into(), value.into_boxed_slice())))
+            })),
+            (None, Some(prefix)) => Box::new(db.scan_prefix(prefix).map(move |i| i.map_err(to_io))),
+            (None, None) => Box::new(db.iter().map(move |i| i.map_err(to_io))),
+        };
+        Box::new(entries.filter(move |item| match item {
+            Ok((key, value)) => {
+                !validation.is_reserved(&String::from_utf8_lossy(key)) && self.matches(key, value)
+            }
+            Err(_) => true,
+        }))
+    }
+
+    fn matches(&self, key: &[u8], value: &[u8]) -> bool {
+        if let Some(prefix) = &self.prefix {
+            if !key.starts_with(prefix.as_bytes()) {
+                return false;
+            }
+        }
+        if self.filter.is_empty() {
+            return true;
+        }
+        let Ok(doc) = serde_json::from_slice::<Value>(value) else {
+            return false;
+        };
+        self.filter
+            .iter()
+            .all(|(path, expected)| doc.pointer(&to_pointer(path)) == Some(expected))
+    }
+}
+
+/// Buffers output and hands it to the response stream in `CHUNK_SIZE` pieces.
+/// Fails with `BrokenPipe` once the client goes away, which stops the export.
+struct ChunkWriter {
+    tx: mpsc::Sender<io::Result<Bytes>>,
+    buf: Vec<u8>,
+}
+
+impl ChunkWriter {
+    fn send(&mut self) -> io::Result<()> {
+        let chunk = Bytes::from(std::mem::take(&mut self.buf));
+        self.tx
+            .blocking_send(Ok(chunk))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "client disconnected"))
+    }
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        if self.buf.len() >= CHUNK_SIZE {
+            self.send()?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            self.send()?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes one `{"key": ..., "value": ...}` line per entry. Values were
+/// validated as JSON on the way in, so they are copied over verbatim.
+fn write_ndjson<W: Write>(entries: impl Iterator<Item = Entry>, out: &mut W) -> io::Result<()> {
+    for item in entries {
+        let (key, value) = item?;
+        out.write_all(b"{\"key\":")?;
+        serde_json::to_writer(&mut *out, &String::from_utf8_lossy(&key))?;
+        out.write_all(b",\"value\":")?;
+        out.write_all(&value)?;
+        out.write_all(b"}\n")?;
+    }
+    Ok(())
+}
+
+/// Writes a header row followed by one row per entry, with the key in the first
+/// column. Without explicit columns, the leaf paths of the first document are
+/// used.
+fn write_csv<W: Write>(
+    entries: impl Iterator<Item = Entry>,
+    columns: Option<Vec<String>>,
+    out: &mut W,
+) -> io::Result<()> {
+    let mut writer = csv::Writer::from_writer(out);
+    let mut columns = columns;
+    if let Some(columns) = &columns {
+        write_header(&mut writer, columns)?;
+    }
+
+    for item in entries {
+        let (key, value) = item?;
+        let value: Value = serde_json::from_slice(&value)?;
+
+        let columns = match &mut columns {
+            Some(columns) => columns,
+            None => {
+                let mut paths = Vec::new();
+                leaf_paths(&value, String::new(), &mut paths);
+                write_header(&mut writer, &paths)?;
+                columns.insert(paths)
+            }
+        };
+
+        let row = columns
+            .iter()
+            .map(|path| match value.pointer(&to_pointer(path)) {
+                None | Some(Value::Null) => String::new(),
+                Some(Value::String(s)) => s.clone(),
+                Some(other) => other.to_string(),
+            });
+        writer
+            .write_record(std::iter::once(String::from_utf8_lossy(&key).into_owned()).chain(row))?;
+    }
+    writer.flush()
+}
+
+fn write_header<W: Write>(writer: &mut csv::Writer<W>, columns: &[String]) -> io::Result<()> {
+    writer.write_record(std::iter::once("key").chain(columns.iter().map(String::as_str)))?;
+    Ok(())
+}
+
+fn leaf_paths(value: &Value, prefix: String, paths: &mut Vec<String>) {
+    let join = |k: &str| {
+        if prefix.is_empty() {
+            k.to_string()
+        } else {
+            format!("{prefix}.{k}")
+        }
+    };
+    match value {
+        Value::Object(map) if !map.is_empty() => {
+            for (k, v) in map {
+                leaf_paths(v, join(k), paths);
+            }
+        }
+        Value::Array(items) if !items.is_empty() => {
+            for (i, v) in items.iter().enumerate() {
+                leaf_paths(v, join(&i.to_string()), paths);
+            }
+        }
+        _ => paths.push(prefix),
+    }
+}
+
+/// Turns `a.b.0` into the JSON pointer `/a/b/0`. The empty path refers to the
+/// whole document, which is the only column of scalar documents.
+pub fn to_pointer(path: &str) -> String {
+    if path.is_empty() {
+        return String::new();
+    }
+    path.split('.').fold(String::new(), |mut pointer, segment| {
+        pointer.push('/');
+        pointer.push_str(&segment.replace('~', "~0").replace('/', "~1"));
+        pointer
+    })
+}
+
+/// A Parquet column picked with `?schema=`, read from a dot delimited path
+#[cfg(feature = "parquet")]
+struct Column {
+    path: String,
+    pointer: String,
+    kind: ColumnKind,
+}
+
+#[cfg(feature = "parquet")]
+#[derive(Clone, Copy)]
+enum ColumnKind {
+    String,
+    Int,
+    Float,
+    Bool,
+    /// Any value, written as JSON text
+    Json,
+}
+
+#[cfg(feature = "parquet")]
+impl Column {
+    /// Reads the comma separated `path:type` list of `?schema=`.
+    fn parse_all(schema: &str) -> Result<Vec<Column>, String> {
+        let mut columns: Vec<Column> = Vec::new();
+        for column in schema.split(',').map(str::trim).filter(|c| !c.is_empty()) {
+            let (path, kind) = column
+                .rsplit_once(':')
+                .ok_or_else(|| format!("column `{column}` must be written as path:type"))?;
+            let kind = match kind {
+                "string" => ColumnKind::String,
+                "int" => ColumnKind::Int,
+                "float" => ColumnKind::Float,
+                "bool" => ColumnKind::Bool,
+                "json" => ColumnKind::Json,
+                _ => {
+                    return Err(format!(
+                        "unknown type `{kind}` for column `{path}`, expected string, int, float, bool or json"
+                    ))
+                }
+            };
+            if path.is_empty() {
+                return Err(format!("column `{column}` needs a path"));
+            }
+            if columns.iter().any(|c| c.path == path) {
+                return Err(format!("column `{path}` is given twice"));
+            }
+            columns.push(Column {
+                path: path.to_string(),
+                pointer: to_pointer(path),
+                kind,
+            });
+        }
+        if columns.is_empty() {
+            return Err("schema needs at least one column".to_string());
+        }
+        Ok(columns)
+    }
+
+    fn data_type(&self) -> arrow_schema::DataType {
+        use arrow_schema::DataType;
+        match self.kind {
+            ColumnKind::String | ColumnKind::Json => DataType::Utf8,
+            ColumnKind::Int => DataType::Int64,
+            ColumnKind::Float => DataType::Float64,
+            ColumnKind::Bool => DataType::Boolean,
+        }
+    }
+
+    /// The column's value in `doc`, or null when it is missing or doesn't fit
+    /// the column's type.
+    fn cell(&self, doc: &Value) -> Value {
+        let Some(value) = doc.pointer(&self.pointer) else {
+            return Value::Null;
+        };
+        match (self.kind, value) {
+            (_, Value::Null) => Value::Null,
+            (ColumnKind::Json, value) => Value::String(value.to_string()),
+            (ColumnKind::String, Value::String(_)) | (ColumnKind::Bool, Value::Bool(_)) => {
+                value.clone()
+            }
+            (ColumnKind::String, Value::Number(_) | Value::Bool(_)) => {
+                Value::String(value.to_string())
+            }
+            (ColumnKind::Int, Value::Number(n)) => n.as_i64().map_or(Value::Null, Value::from),
+            (ColumnKind::Float, Value::Number(n)) => n.as_f64().map_or(Value::Null, Value::from),
+            _ => Value::Null,
+        }
+    }
+}
+
+/// Wraps scalars found where the schema has a list into a one element list,
+/// which is how inference merged them.
+#[cfg(feature = "parquet")]
+fn conform(value: Value, data_type: &arrow_schema::DataType) -> Value {
+    use arrow_schema::DataType;
+    match (value, data_type) {
+        (Value::Null, _) => Value::Null,
+        (Value::Array(items), DataType::List(item)) => Value::Array(
+            items
+                .into_iter()
+                .map(|v| conform(v, item.data_type()))
+                .collect(),
+        ),
+        (value, DataType::List(item)) => Value::Array(vec![conform(value, item.data_type())]),
+        (Value::Object(map), DataType::Struct(fields)) => Value::Object(
+            map.into_iter()
+                .map(|(k, v)| match fields.find(&k) {
+                    Some((_, field)) => (k, conform(v, field.data_type())),
+                    None => (k, v),
+                })
+                .collect(),
+        ),
+        (value, _) => value,
+    }
+}
+
+/// Writes `{key, value}` rows as Parquet. With `columns`, `value` is a struct
+/// of those columns. Otherwise its schema is inferred from every stored
+/// document in a first pass, and documents whose shapes can't be merged into
+/// one schema are written as JSON text instead. Rows are decoded in batches.
+#[cfg(feature = "parquet")]
+fn write_parquet<W: Write + Send>(
+    db: &RocksDB,
+    validation: &Validation,
+    selection: &Selection,
+    columns: Option<Vec<Column>>,
+    out: &mut W,
+) -> io::Result<()> {
+    use arrow_json::reader::{infer_json_schema_from_iterator, ReaderBuilder};
+    use arrow_schema::{ArrowError, DataType, Field, Schema};
+    use parquet::arrow::ArrowWriter;
+    use std::sync::Arc;
+
+    const BATCH_SIZE: usize = 1024;
+    let to_io =
+        |e: Box<dyn std::error::Error + Send + Sync>| io::Error::new(io::ErrorKind::Other, e);
+
+    let documents = || {
+        selection.entries(db, validation).map(|item| {
+            let (key, value) = item?;
+            let value: Value = serde_json::from_slice(&value)?;
+            Ok::<_, io::Error>((String::from_utf8_lossy(&key).into_owned(), value))
+        })
+    };
+    let key = Field::new("key", DataType::Utf8, true);
+
+    let (schema, to_value): (Schema, Box<dyn Fn(Value) -> Value>) = match columns {
+        Some(columns) => {
+            let fields: Vec<Field> = columns
+                .iter()
+                .map(|c| Field::new(&c.path, c.data_type(), true))
+                .collect();
+            let value = Field::new("value", DataType::Struct(fields.into()), true);
+            let to_value = move |doc: Value| {
+                let row: Map<String, Value> = columns
+                    .iter()
+                    .map(|c| (c.path.clone(), c.cell(&doc)))
+                    .collect();
+                Value::Object(row)
+            };
+            (Schema::new(vec![key, value]), Box::new(to_value))
+        }
+        None => {
+            let inferred = infer_json_schema_from_iterator(documents().map(|doc| {
+                doc.map(|(key, value)| serde_json::json!({ "key": key, "value": value }))
+                    .map_err(|e| ArrowError::ExternalError(e.into()))
+            }));
+            match inferred {
+                Ok(schema) => {
+                    let value_type = schema
+                        .field_with_name("value")
+                        .map(|f| f.data_type().clone())
+                        .unwrap_or(DataType::Null);
+                    (schema, Box::new(move |doc| conform(doc, &value_type)))
+                }
+                Err(ArrowError::ExternalError(e)) => return Err(to_io(e)),
+                Err(e) => {
+                    log::info!("Writing values as JSON text, no common schema: {}", e);
+                    let value = Field::new("value", DataType::Utf8, true);
+                    let to_value = |doc: Value| Value::String(doc.to_string());
+                    (Schema::new(vec![key, value]), Box::new(to_value))
+                }
+            }
+        }
+    };
+    let schema = Arc::new(schema);
+
+    let mut decoder = ReaderBuilder::new(schema.clone())
+        .with_batch_size(BATCH_SIZE)
+        .with_coerce_primitive(true)
+        .build_decoder()
+        .map_err(|e| to_io(e.into()))?;
+    let mut writer = ArrowWriter::try_new(out, schema, None).map_err(|e| to_io(e.into()))?;
+
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    let mut rows = documents().peekable();
+    while let Some(row) = rows.next() {
+        let (key, value) = row?;
+        batch.push(serde_json::json!({ "key": key, "value": to_value(value) }));
+        if batch.len() == BATCH_SIZE || rows.peek().is_none() {
+            decoder.serialize(&batch).map_err(|e| to_io(e.into()))?;
+            batch.clear();
+            if let Some(records) = decoder.flush().map_err(|e| to_io(e.into()))? {
+                writer.write(&records).map_err(|e| to_io(e.into()))?;
+            }
+        }
+    }
+    writer.close().map_err(|e| to_io(e.into()))?;
+    Ok(())
+}
+
+fn write_export<W: Write + Send>(
+    db: &RocksDB,
+    validation: &Validation,
+    params: ExportParams,
+    selection: &Selection,
+    out: &mut W,
+) -> io::Result<()> {
+    match params.format {
+        Format::Ndjson => write_ndjson(selection.entries(db, validation), out),
+        Format::Csv => {
+            let columns = params.columns.map(|c| {
+                c.split(',')
+                    .map(|c| c.trim().to_string())
+                    .filter(|c| !c.is_empty())
+                    .collect()
+            });
+            write_csv(selection.entries(db, validation), columns, out)
+        }
+        #[cfg(feature = "parquet")]
+        Format::Parquet => {
+            let columns = params
+                .parquet_columns()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            write_parquet(db, validation, selection, columns, out)
+        }
+    }
+}
+
+pub async fn export(
+    db: Data<RocksDB>,
+    validation: Data<Validation>,
+    token: Data<String>,
+    params: Query<ExportParams>,
+    req: HttpRequest,
+) -> HttpResponse {
+    if !auth::is_admin(&req, &token) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    stream(db, validation, params.into_inner(), Selection::default())
+}
+
+/// Like `export`, but only for the entries picked by the `Selection` in the body.
+pub async fn export_selected(
+    db: Data<RocksDB>,
+    validation: Data<Validation>,
+    token: Data<String>,
+    params: Query<ExportParams>,
+    selection: Json<Selection>,
+    req: HttpRequest,
+) -> HttpResponse {
+    if !auth::is_admin(&req, &token) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    stream(db, validation, params.into_inner(), selection.into_inner())
+}
+
+fn stream(
+    db: Data<RocksDB>,
+    validation: Data<Validation>,
+    params: ExportParams,
+    selection: Selection,
+) -> HttpResponse {
+    #[cfg(feature = "parquet")]
+    if let Err(msg) = params.parquet_columns() {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .body(serde_json::json!({ "status": 400, "msg": msg }).to_string());
+    }
+    let (format, gzip) = (params.format, params.gzip);
+    let (tx, rx) = mpsc::channel(16);
+    task::spawn_blocking(move || {
+        let errors = tx.clone();
+        let mut writer = ChunkWriter {
+            tx,
+            buf: Vec::with_capacity(CHUNK_SIZE),
+        };
+        let result = if gzip {
+            let mut encoder = GzEncoder::new(writer, Compression::default());
+            write_export(&db, &validation, params, &selection, &mut encoder)
+                .and_then(|_| encoder.finish()?.flush())
+        } else {
+            write_export(&db, &validation, params, &selection, &mut writer)
+                .and_then(|_| writer.flush())
+        };
+        if let Err(e) = result {
+            log::error!("Export failed: {}", e);
+            // Surfacing the error aborts the response instead of ending it cleanly
+            let _ = errors.blocking_send(Err(e));
+        }
+    });
+
+    let (content_type, file) = match format {
+        Format::Ndjson => ("application/x-ndjson", "export.ndjson"),
+        Format::Csv => ("text/csv", "export.csv"),
+        #[cfg(feature = "parquet")]
+        Format::Parquet => ("application/vnd.apache.parquet", "export.parquet"),
+    };
+    let mut res = HttpResponse::Ok();
+    if gzip {
+        res.content_type("application/gzip").insert_header((
+            "Content-Disposition",
+            format!("attachment; filename=\"{file}.gz\""),
+        ));
+    } else {
+        res.content_type(content_type);
+    }
+    res.streaming(ReceiverStream::new(rx))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn csv(docs: &[(&str, &str)], columns: Option<&[&str]>) -> String {
+        let entries = docs
+            .iter()
+            .map(|(k, v)| Ok((k.as_bytes().into(), v.as_bytes().into())));
+        let columns = columns.map(|c| c.iter().map(|c| c.to_string()).collect());
+        let mut out = Vec::new();
+        write_csv(entries, columns, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn scalar_documents_get_a_single_column() {
+        assert_eq!(csv(&[("a", "5"), ("b", "\"x\"")], None), "key,\na,5\nb,x\n");
+    }
+
+    #[test]
+    fn value_is_an_ordinary_field() {
+        let docs = [("a", r#"{"value":1,"other":2}"#)];
+        assert_eq!(csv(&docs, None), "key,other,value\na,2,1\n");
+        assert_eq!(csv(&docs, Some(&["value"])), "key,value\na,1\n");
+        assert_eq!(csv(&[("a", "5")], Some(&["value"])), "key,value\na,\n");
+    }
+
+    #[test]
+    fn the_empty_path_is_the_whole_document() {
+        let doc = json!({ "value": 1, "": 2 });
+        assert_eq!(doc.pointer(&to_pointer("")), Some(&doc));
+        assert_eq!(doc.pointer(&to_pointer("value")), Some(&json!(1)));
+    }
+
+    #[cfg(feature = "parquet")]
+    fn parquet(docs: &[(&str, &str)], schema: Option<&str>) -> (String, Vec<Value>) {
+        use crate::kv::KVStore;
+        use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+        let tmp = crate::kv::TempDB::open();
+        for (key, value) in docs {
+            tmp.db.save(key, value);
+        }
+        let columns = schema.map(|s| Column::parse_all(s).unwrap());
+        let mut out = Vec::new();
+        let validation = Validation::from_env();
+        write_parquet(
+            &tmp.db,
+            &validation,
+            &Selection::default(),
+            columns,
+            &mut out,
+        )
+        .unwrap();
+
+        let reader = ParquetRecordBatchReaderBuilder::try_new(Bytes::from(out))
+            .unwrap()
+            .build()
+            .unwrap();
+        let mut types = String::new();
+        let mut json = arrow_json::LineDelimitedWriter::new(Vec::new());
+        for batch in reader {
+            let batch = batch.unwrap();
+            types = batch
+                .schema()
+                .field_with_name("value")
+                .unwrap()
+                .data_type()
+                .to_string();
+            json.write(&batch).unwrap();
+        }
+        json.finish().unwrap();
+        let rows = String::from_utf8(json.into_inner()).unwrap();
+        let rows = rows
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        (types, rows)
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn mixed_scalars_are_coerced() {
+        let (_, rows) = parquet(&[("a", r#"{"n":1}"#), ("b", r#"{"n":"x"}"#)], None);
+        assert_eq!(rows[0], json!({ "key": "a", "value": { "n": "1" } }));
+        assert_eq!(rows[1], json!({ "key": "b", "value": { "n": "x" } }));
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn scalars_merged_into_lists_become_lists() {
+        let (_, rows) = parquet(&[("a", r#"{"n":[1,2]}"#), ("b", r#"{"n":3}"#)], None);
+        assert_eq!(rows[1], json!({ "key": "b", "value": { "n": [3] } }));
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn documents_without_a_common_schema_are_written_as_json() {
+        let docs = [("a", r#"{"n":{"m":1}}"#), ("b", r#"{"n":2}"#), ("c", "3")];
+        let (types, rows) = parquet(&docs, None);
+        assert_eq!(types, "Utf8");
+        assert_eq!(rows[0], json!({ "key": "a", "value": r#"{"n":{"m":1}}"# }));
+        assert_eq!(rows[2], json!({ "key": "c", "value": "3" }));
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn supplied_schemas_pick_and_type_columns() {
+        let docs = [
+            ("a", r#"{"name":"x","age":3,"tags":["t"]}"#),
+            ("b", r#"{"name":{"first":"y"},"age":"old"}"#),
+        ];
+        let (_, rows) = parquet(&docs, Some("name:string,age:int,tags:json"));
+        assert_eq!(
+            rows[0],
+            json!({ "key": "a", "value": { "name": "x", "age": 3, "tags": r#"["t"]"# } })
+        );
+        assert_eq!(rows[1], json!({ "key": "b", "value": {} }));
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn bad_schemas_are_rejected() {
+        for schema in ["name", "name:text", ":int", "a:int,a:bool", ","] {
+            assert!(Column::parse_all(schema).is_err(), "{schema}");
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use crate::encoding;
+use crate::kv::{KVStore, RocksDB};
+use crate::validation::Validation;
+
+use actix_web::{
+    web::{Data, Path, Query},
+    HttpRequest, HttpResponse,
+};
+use bytes::Bytes;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+#[derive(Deserialize)]
+pub struct FieldParams {
+    /// JSON pointer to the array inside the stored document, e.g. `/tags`.
+    /// Empty means the document itself.
+    #[serde(default)]
+    field: String,
+}
+
+fn conflict(msg: String) -> HttpResponse {
+    HttpResponse::Conflict()
+        .content_type("application/json")
+        .body(json!({ "status": 409, "msg": msg }).to_string())
+}
+
+/// Walks `pointer` into `doc`, creating missing object members along the way
+/// and an empty array at the end.
+fn array_at<'a>(doc: &'a mut Value, pointer: &str) -> Result<&'a mut Vec<Value>, String> {
+    if !pointer.is_empty() && !pointer.starts_with('/') {
+        return Err(format!("{pointer:?} is not a JSON pointer"));
+    }
+    let segments: Vec<String> = pointer
+        .split('/')
+        .skip(1)
+        .map(|s| s.replace("~1", "/").replace("~0", "~"))
+        .collect();
+
+    let mut current = doc;
+    for (i, segment) in segments.iter().enumerate() {
+        let last = i == segments.len() - 1;
+        current = match current {
+            Value::Object(map) => map.entry(segment.as_str()).or_insert_with(|| {
+                if last {
+                    Value::Array(Vec::new())
+                } else {
+                    Value::Object(Map::new())
+                }
+            }),
+            Value::Array(items) => segment
+                .parse::<usize>()
+                .ok()
+                .and_then(|index| items.get_mut(index))
+                .ok_or_else(|| format!("{pointer:?} points past the end of an array"))?,
+            _ => {
+                return Err(format!(
+                    "{pointer:?} goes through a value that is not a container"
+                ))
+            }
+        };
+    }
+    match current {
+        Value::Array(items) => Ok(items),
+        _ => Err(format!("{pointer:?} is not an array")),
+    }
+}
+
+/// Runs `f` on the array field of the document stored at `key` atomically.
+/// Missing documents start out as an empty object, or an empty array when
+/// `pointer` addresses the whole document. Changes that grow the document
+/// past the maximum value size are dropped.
+pub fn modify_array<T>(
+    db: &RocksDB,
+    validation: &Validation,
+    key: &str,
+    pointer: &str,
+    f: impl FnOnce(&mut Vec<Value>) -> T,
+) -> Result<T, HttpResponse> {
+    db.update(key, |current| {
+        let mut doc = match current.as_deref().map(serde_json::from_str::<Value>) {
+            None if pointer.is_empty() => Value::Array(Vec::new()),
+            None => Value::Object(Map::new()),
+            Some(Ok(doc)) => doc,
+            Some(Err(_)) => return (current, Err(conflict("Stored value is not JSON".into()))),
+        };
+        match array_at(&mut doc, pointer) {
+            Ok(items) => {
+                let result = f(items);
+                // Removing from a missing document shouldn't create it
+                let next = match (&current, items.is_empty()) {
+                    (None, true) => None,
+                    _ => Some(doc.to_string()),
+                };
+                let size = |v: &Option<String>| v.as_ref().map_or(0, String::len);
+                if size(&next) > validation.max_value_size() && size(&next) > size(&current) {
+                    return (current, Err(validation.too_large()));
+                }
+                (next, Ok(result))
+            }
+            Err(msg) => (current, Err(conflict(msg))),
+        }
+    })
+    .unwrap_or_else(|e| {
+        log::error!("Error updating {}: {}", key, e);
+        Err(HttpResponse::InternalServerError()
+            .content_type("application/json")
+            .finish())
+    })
+}
+
+#[derive(Deserialize)]
+pub struct AppendParams {
+    #[serde(default)]
+    field: String,
+    /// Longest the array may grow; the oldest elements are dropped past it
+    max: Option<usize>,
+}
+
+/// Parses the body as a JSON (or CBOR) array.
+fn array_body(
+    validation: &Validation,
+    req: &HttpRequest,
+    body: &Bytes,
+) -> Result<Vec<Value>, HttpResponse> {
+    validation.check_value(body)?;
+    match encoding::decode(req, body)? {
+        Value::Array(items) => Ok(items),
+        _ => Err(HttpResponse::BadRequest()
+            .content_type("application/json")
+            .body(json!({ "status": 400, "msg": "Body must be an array" }).to_string())),
+    }
+}
+
+/// Adds the members in the body to the array field, skipping ones already in it.
+pub async fn sadd(
+    key: Path<String>,
+    db: Data<RocksDB>,
+    validation: Data<Validation>,
+    params: Query<FieldParams>,
+    body: Bytes,
+    req: HttpRequest,
+) -> HttpResponse {
+    let key = key.into_inner();
+    let members = match validation
+        .check_key(&key)
+        .and_then(|_| array_body(&validation, &req, &body))
+    {
+        Ok(members) => members,
+        Err(res) => return res,
+    };
+
+    let result = modify_array(&db, &validation, &key, &params.field, |set| {
+        let before = set.len();
+        for member in members {
+            if !set.contains(&member) {
+                set.push(member);
+            }
+        }
+        json!({ "added": set.len() - before, "size": set.len() })
+    });
+    match result {
+        Ok(res) => encoding::respond(&req, HttpResponse::Ok(), &res),
+        Err(res) => res,
+    }
+}
+
+/// Removes the members in the body from the array field.
+pub async fn srem(
+    key: Path<String>,
+    db: Data<RocksDB>,
+    validation: Data<Validation>,
+    params: Query<FieldParams>,
+    body: Bytes,
+    req: HttpRequest,
+) -> HttpResponse {
+    let key = key.into_inner();
+    let members = match validation
+        .check_key(&key)
+        .and_then(|_| array_body(&validation, &req, &body))
+    {
+        Ok(members) => members,
+        Err(res) => return res,
+    };
+
+    let result = modify_array(&db, &validation, &key, &params.field, |set| {
+        let before = set.len();
+        set.retain(|m| !members.contains(m));
+        json!({ "removed": before - set.len(), "size": set.len() })
+    });
+    match result {
+        Ok(res) => encoding::respond(&req, HttpResponse::Ok(), &res),
+        Err(res) => res,
+    }
+}
+
+/// Pushes the elements in the body onto the end of the array field, trimming
+/// it from the front to `max` elements if given.
+pub async fn append(
+    key: Path<String>,
+    db: Data<RocksDB>,
+    validation: Data<Validation>,
+    params: Query<AppendParams>,
+    body: Bytes,
+    req: HttpRequest,
+) -> HttpResponse {
+    let key = key.into_inner();
+    let elements = match validation
+        .check_key(&key)
+        .and_then(|_| array_body(&validation, &req, &body))
+    {
+        Ok(elements) => elements,
+        Err(res) => return res,
+    };
+
+    let result = modify_array(&db, &validation, &key, &params.field, |items| {
+        items.extend(elements);
+        let trimmed = params.max.map_or(0, |max| items.len().saturating_sub(max));
+        items.drain(..trimmed);
+        json!({ "length": items.len(), "trimmed": trimmed })
+    });
+    match result {
+        Ok(res) => encoding::respond(&req, HttpResponse::Ok(), &res),
+        Err(res) => res,
+    }
+}
+
+pub async fn smembers(
+    key: Path<String>,
+    db: Data<RocksDB>,
+    validation: Data<Validation>,
+    params: Query<FieldParams>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let key = key.into_inner();
+    if let Err(res) = validation.check_key(&key) {
+        return res;
+    }
+    let doc = match db.find(&key) {
+        Some(v) => serde_json::from_str::<Value>(&v).unwrap_or(Value::Null),
+        None => {
+            return HttpResponse::NotFound()
+                .content_type("application/json")
+                .finish()
+        }
+    };
+    match doc.pointer(&params.field) {
+        Some(Value::Array(set)) => encoding::respond(&req, HttpResponse::Ok(), &json!(set)),
+        None => encoding::respond(&req, HttpResponse::Ok(), &json!([])),
+        Some(_) => conflict(format!("{:?} is not an array", params.field)),
+    }
+}
@@ -0,0 +1,119 @@
+use crate::auth;
+use crate::kv::{KVStore, RocksDB};
+use crate::leases;
+use crate::validation::Validation;
+
+use actix_web::{
+    web::{block, Data},
+    HttpRequest, HttpResponse,
+};
+use bytes::Bytes;
+use serde_json::{json, Map, Value};
+
+fn internal_error() -> HttpResponse {
+    HttpResponse::InternalServerError()
+        .content_type("application/json")
+        .finish()
+}
+
+/// Every user key with its value, leaving out internal state under reserved
+/// prefixes. Keys come out sorted, so dumps of equal data are identical.
+fn dump(db: &RocksDB, validation: &Validation) -> Result<Map<String, Value>, String> {
+    let mut fixture = Map::new();
+    for item in db.iter() {
+        let (k, v) = item.map_err(|e| e.to_string())?;
+        let key = String::from_utf8_lossy(&k).into_owned();
+        if validation.is_reserved(&key) {
+            continue;
+        }
+        let value = serde_json::from_slice(&v).map_err(|e| format!("{key}: {e}"))?;
+        fixture.insert(key, value);
+    }
+    Ok(fixture)
+}
+
+/// Replaces every user key with the ones in `fixture`.
+fn load(db: &RocksDB, validation: &Validation, fixture: Map<String, Value>) -> Result<(), String> {
+    for (key, _) in dump(db, validation)? {
+        if !db.delete(&key) {
+            return Err(format!("could not delete {key}"));
+        }
+        leases::release(db, &key);
+    }
+    let entries: Vec<(String, String)> = fixture
+        .into_iter()
+        .map(|(k, v)| (k, v.to_string()))
+        .collect();
+    db.write_batch(entries.iter().map(|(k, v)| (k.as_str(), v.as_str())))
+        .map_err(|e| e.to_string())?;
+    for (key, _) in &entries {
+        leases::release(db, key);
+    }
+    Ok(())
+}
+
+/// Dumps all keys as one pretty-printed JSON object.
+pub async fn get(
+    db: Data<RocksDB>,
+    validation: Data<Validation>,
+    token: Data<String>,
+    req: HttpRequest,
+) -> HttpResponse {
+    if !auth::is_admin(&req, &token) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    match block(move || dump(&db, &validation)).await {
+        Ok(Ok(fixture)) => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(serde_json::to_string_pretty(&fixture).unwrap()),
+        Ok(Err(e)) => {
+            log::error!("Error dumping fixture: {}", e);
+            internal_error()
+        }
+        Err(_) => internal_error(),
+    }
+}
+
+/// Resets the store to the JSON object in the body, deleting every key not in
+/// it. Reserved internal state is left alone.
+pub async fn put(
+    db: Data<RocksDB>,
+    validation: Data<Validation>,
+    token: Data<String>,
+    body: Bytes,
+    req: HttpRequest,
+) -> HttpResponse {
+    if !auth::is_admin(&req, &token) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let fixture =
+        match serde_json::from_slice::<Value>(&body) {
+            Ok(Value::Object(fixture)) => fixture,
+            _ => return HttpResponse::BadRequest()
+                .content_type("application/json")
+                .body(
+                json!({ "status": 400, "msg": "Fixture must be a JSON object of keys to values" })
+                    .to_string(),
+            ),
+        };
+    for (key, value) in &fixture {
+        if let Err(res) = validation
+            .check_key(key)
+            .and_then(|_| validation.check_value(value.to_string().as_bytes()))
+        {
+            return res;
+        }
+    }
+
+    let count = fixture.len();
+    match block(move || load(&db, &validation, fixture)).await {
+        Ok(Ok(())) => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(json!({ "loaded": count }).to_string()),
+        Ok(Err(e)) => {
+            log::error!("Error loading fixture: {}", e);
+            internal_error()
+        }
+        Err(_) => internal_error(),
+    }
+}
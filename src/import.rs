@@ -0,0 +1,231 @@
+use crate::auth;
+use crate::kv::{self, RocksDB};
+use crate::leases;
+use crate::validation::Validation;
+
+use actix_web::{
+    dev::Decompress,
+    web::{block, Data, Payload},
+    HttpRequest, HttpResponse,
+};
+use bytes::BytesMut;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio_stream::StreamExt;
+
+/// Entries written per batch while importing.
+const BATCH_SIZE: usize = 1000;
+
+/// One line of an NDJSON export.
+#[derive(Deserialize)]
+struct Line {
+    key: String,
+    value: Value,
+}
+
+fn bad_request(msg: String) -> HttpResponse {
+    HttpResponse::BadRequest()
+        .content_type("application/json")
+        .body(json!({ "status": 400, "msg": msg }).to_string())
+}
+
+async fn write(db: &Data<RocksDB>, batch: Vec<(String, String)>) -> Result<(), HttpResponse> {
+    let db = db.clone();
+    let written = block(move || {
+        db.write_batch(batch.iter().map(|(k, v)| (k.as_str(), v.as_str())))?;
+        for (key, _) in &batch {
+            leases::release(&db, key);
+        }
+        Ok::<_, kv::Error>(())
+    })
+    .await;
+    match written {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(e)) => {
+            log::error!("Error writing imported entries: {}", e);
+            Err(HttpResponse::InternalServerError()
+                .content_type("application/json")
+                .finish())
+        }
+        Err(_) => Err(HttpResponse::InternalServerError()
+            .content_type("application/json")
+            .finish()),
+    }
+}
+
+/// Loads entries streamed in the NDJSON format written by `GET /api/_export`,
+/// overwriting existing keys. The body is read and written in batches as it
+/// arrives, so it can be piped straight from another instance's export. A
+/// gzip compressed body is accepted with `Content-Encoding: gzip`.
+///
+/// Keys under reserved prefixes are skipped. Stops at the first bad line or
+/// invalid key; batches written before it are kept.
+pub async fn import(
+    db: Data<RocksDB>,
+    validation: Data<Validation>,
+    token: Data<String>,
+    payload: Payload,
+    req: HttpRequest,
+) -> HttpResponse {
+    if !auth::is_admin(&req, &token) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let mut payload = Decompress::from_headers(payload, req.headers());
+    let mut buf = BytesMut::new();
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    let mut imported = 0;
+    let mut skipped = 0;
+    let mut line_no = 0;
+    // How much of `buf` is known not to contain a newline
+    let mut scanned = 0;
+    let mut done = false;
+    while !done {
+        match payload.next().await {
+            Some(Ok(chunk)) => buf.extend_from_slice(&chunk),
+            Some(Err(e)) => return bad_request(e.to_string()),
+            // Whatever is left is the last line, which may lack a newline
+            None => {
+                buf.extend_from_slice(b"\n");
+                done = true;
+            }
+        }
+
+        while let Some(end) = buf[scanned..].iter().position(|b| *b == b'\n') {
+            let line = buf.split_to(scanned + end + 1);
+            scanned = 0;
+            line_no += 1;
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+            let entry = match serde_json::from_slice::<Line>(&line) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    return bad_request(format!(
+                        "line {line_no}: {e}; {imported} entries were imported before it"
+                    ))
+                }
+            };
+            // Internal state belongs to the instance it was exported from
+            if validation.is_reserved(&entry.key) {
+                skipped += 1;
+                continue;
+            }
+            if let Err(res) = validation.check_key(&entry.key) {
+                return res;
+            }
+            let value = entry.value.to_string();
+            if let Err(res) = validation.check_value(value.as_bytes()) {
+                return res;
+            }
+            batch.push((entry.key, value));
+            if batch.len() == BATCH_SIZE {
+                imported += batch.len();
+                if let Err(res) = write(&db, std::mem::take(&mut batch)).await {
+                    return res;
+                }
+            }
+        }
+        scanned = buf.len();
+        // A line can't be longer than the largest value plus its key and framing
+        if buf.len() > validation.max_value_size() * 2 {
+            return bad_request(format!("line {} is too long", line_no + 1));
+        }
+    }
+    imported += batch.len();
+    if let Err(res) = write(&db, batch).await {
+        return res;
+    }
+
+    log::info!("Imported {} entries, skipped {}", imported, skipped);
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(json!({ "imported": imported, "skipped": skipped }).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv::{KVStore, TempDB};
+    use actix_web::{body::to_bytes, http::StatusCode, test::TestRequest, FromRequest};
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+
+    async fn run(db: &RocksDB, req: TestRequest) -> HttpResponse {
+        let (req, mut payload) = req.to_http_parts();
+        let payload = Payload::from_request(&req, &mut payload).await.unwrap();
+        let db = Data::new(db.clone());
+        let validation = Data::new(Validation::from_env());
+        import(db, validation, Data::new("admin".to_string()), payload, req).await
+    }
+
+    fn request(body: impl Into<bytes::Bytes>) -> TestRequest {
+        TestRequest::post()
+            .insert_header(("Authorization", "admin"))
+            .set_payload(body)
+    }
+
+    async fn json_body(res: HttpResponse) -> Value {
+        serde_json::from_slice(&to_bytes(res.into_body()).await.unwrap()).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn imports_every_line() {
+        let tmp = TempDB::open();
+        // Blank lines are skipped and the last one may lack a newline
+        let body = "{\"key\":\"a\",\"value\":{\"n\":1}}\n\n{\"key\":\"b\",\"value\":[1,2]}";
+        let res = run(&tmp.db, request(body)).await;
+        assert_eq!(json_body(res).await, json!({ "imported": 2, "skipped": 0 }));
+        assert_eq!(tmp.db.find("a").as_deref(), Some(r#"{"n":1}"#));
+        assert_eq!(tmp.db.find("b").as_deref(), Some("[1,2]"));
+    }
+
+    #[actix_web::test]
+    async fn reads_gzip_bodies() {
+        let tmp = TempDB::open();
+        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+        gz.write_all(b"{\"key\":\"a\",\"value\":1}\n").unwrap();
+        let req = request(gz.finish().unwrap()).insert_header(("Content-Encoding", "gzip"));
+        let res = run(&tmp.db, req).await;
+        assert_eq!(json_body(res).await["imported"], 1);
+        assert_eq!(tmp.db.find("a").as_deref(), Some("1"));
+    }
+
+    #[actix_web::test]
+    async fn stops_at_the_first_bad_line() {
+        let tmp = TempDB::open();
+        let body = "{\"key\":\"a\",\"value\":1}\nnot json\n{\"key\":\"b\",\"value\":1}\n";
+        let res = json_body(run(&tmp.db, request(body)).await).await;
+        assert_eq!(res["status"], 400);
+        assert!(res["msg"].as_str().unwrap().starts_with("line 2:"));
+        assert!(tmp.db.find("b").is_none());
+    }
+
+    #[actix_web::test]
+    async fn requires_the_admin_token() {
+        let tmp = TempDB::open();
+        let req = TestRequest::post().set_payload("{\"key\":\"a\",\"value\":1}\n");
+        let res = run(&tmp.db, req).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert!(tmp.db.find("a").is_none());
+    }
+
+    #[actix_web::test]
+    async fn skips_internal_state() {
+        let tmp = TempDB::open();
+        tmp.db.save("_seq/orders", "7");
+        let body = "{\"key\":\"_seq/orders\",\"value\":1}\n{\"key\":\"a\",\"value\":1}\n";
+        let res = run(&tmp.db, request(body)).await;
+        assert_eq!(json_body(res).await, json!({ "imported": 1, "skipped": 1 }));
+        assert_eq!(tmp.db.find("_seq/orders").as_deref(), Some("7"));
+    }
+
+    #[actix_web::test]
+    async fn rejects_invalid_keys() {
+        let tmp = TempDB::open();
+        let body = "{\"key\":\"bad key/with?chars\",\"value\":1}\n";
+        let res = run(&tmp.db, request(body)).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert!(tmp.db.find("bad key/with?chars").is_none());
+    }
+}
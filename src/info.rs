@@ -0,0 +1,80 @@
+use crate::auth;
+use crate::kv::RocksDB;
+
+use actix_web::{
+    web::{block, Data},
+    HttpRequest, HttpResponse,
+};
+use serde_json::json;
+use std::{fs, io, path::Path, time::Instant};
+
+/// Startup settings reported by `GET /api/_info`.
+pub struct ServerInfo {
+    pub started: Instant,
+    pub workers: usize,
+    pub db_path: String,
+    pub read_only: bool,
+}
+
+fn features() -> Vec<&'static str> {
+    let mut features = Vec::new();
+    if cfg!(feature = "rocksdb") {
+        features.push("rocksdb");
+    }
+    if cfg!(feature = "memory") {
+        features.push("memory");
+    }
+    if cfg!(feature = "parquet") {
+        features.push("parquet");
+    }
+    features
+}
+
+/// Total size of the files in the database directory, WAL and options
+/// included. RocksDB keeps everything in one flat directory.
+fn dir_size(path: &Path) -> io::Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        let metadata = entry?.metadata()?;
+        if metadata.is_file() {
+            size += metadata.len();
+        }
+    }
+    Ok(size)
+}
+
+pub async fn info(
+    db: Data<RocksDB>,
+    token: Data<String>,
+    server: Data<ServerInfo>,
+    req: HttpRequest,
+) -> HttpResponse {
+    if !auth::is_admin(&req, &token) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let path = server.db_path.clone();
+    let disk_bytes = match block(move || dir_size(Path::new(&path))).await {
+        Ok(Ok(size)) => Some(size),
+        Ok(Err(e)) => {
+            log::warn!("Error measuring {}: {}", server.db_path, e);
+            None
+        }
+        Err(_) => None,
+    };
+    let body = json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "git_hash": option_env!("GIT_HASH").unwrap_or("unknown"),
+        "features": features(),
+        "uptime_secs": server.started.elapsed().as_secs(),
+        "workers": server.workers,
+        "db_path": server.db_path,
+        "read_only": server.read_only,
+        "estimated_keys": db.property_int("rocksdb.estimate-num-keys"),
+        "sst_bytes": db.property_int("rocksdb.total-sst-files-size"),
+        "disk_bytes": disk_bytes,
+    });
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(body.to_string())
+}
@@ -1,5 +1,25 @@
-use rocksdb::DB;
-use std::sync::Arc;
+#[cfg(all(feature = "rocksdb", feature = "memory"))]
+compile_error!(
+    "the `rocksdb` and `memory` backends exclude each other, build with `--no-default-features --features memory`"
+);
+#[cfg(not(any(feature = "rocksdb", feature = "memory")))]
+compile_error!("enable a storage backend: `rocksdb` (the default) or `memory`");
+
+#[cfg(feature = "memory")]
+use crate::memory as engine;
+#[cfg(all(feature = "rocksdb", not(feature = "memory")))]
+use rocksdb as engine;
+
+use crate::shadow::Shadow;
+pub use engine::Error;
+use engine::{
+    checkpoint::Checkpoint, BlockBasedOptions, BottommostLevelCompaction, Cache, CompactOptions,
+    Direction, IteratorMode, Options, WriteBatch, WriteBufferManager, DB,
+};
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+};
 
 pub trait KVStore {
     fn init(file_path: &str) -> Self;
@@ -11,17 +31,236 @@ pub trait KVStore {
 #[derive(Clone)]
 pub struct RocksDB {
     db: Arc<DB>,
+    secondary: bool,
+    update_lock: Arc<Mutex<()>>,
+    shadow: Option<Arc<Shadow>>,
+}
+
+impl RocksDB {
+    /// Opens the database at `file_path` with block cache and memtables
+    /// sharing one `memory_budget` in bytes. Memtables may take up to half of
+    /// it; writes stall rather than go over.
+    pub fn init_bounded(file_path: &str, memory_budget: usize) -> Self {
+        let cache = Cache::new_lru_cache(memory_budget);
+        let write_buffers = WriteBufferManager::new_write_buffer_manager_with_cache(
+            memory_budget / 2,
+            true,
+            cache.clone(),
+        );
+        let mut table = BlockBasedOptions::default();
+        table.set_block_cache(&cache);
+
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        opts.set_write_buffer_manager(&write_buffers);
+        opts.set_block_based_table_factory(&table);
+        RocksDB {
+            db: Arc::new(DB::open(&opts, file_path).unwrap()),
+            secondary: false,
+            update_lock: Arc::new(Mutex::new(())),
+            shadow: None,
+        }
+    }
+
+    /// Opens the database at `primary_path` as a read-only secondary instance
+    /// that follows the primary's writes. `secondary_path` holds its own logs.
+    pub fn init_secondary(primary_path: &str, secondary_path: &str) -> Self {
+        let mut opts = Options::default();
+        // Secondaries need every file kept open to follow the primary
+        opts.set_max_open_files(-1);
+        RocksDB {
+            db: Arc::new(DB::open_as_secondary(&opts, primary_path, secondary_path).unwrap()),
+            secondary: true,
+            update_lock: Arc::new(Mutex::new(())),
+            shadow: None,
+        }
+    }
+
+    /// Copies every write made from now on to `shadow`.
+    pub fn with_shadow(self, shadow: Arc<Shadow>) -> Self {
+        RocksDB {
+            shadow: Some(shadow),
+            ..self
+        }
+    }
+
+    /// Runs a plain write under the update lock while shadowing, so its
+    /// copy is queued in the same order as the writes themselves.
+    fn ordered<T>(&self, write: impl FnOnce() -> T) -> T {
+        let _guard = self
+            .shadow
+            .as_ref()
+            .map(|_| self.update_lock.lock().unwrap_or_else(|e| e.into_inner()));
+        write()
+    }
+
+    fn mirror(&self, k: &str, v: Option<&str>) {
+        if let Some(shadow) = &self.shadow {
+            shadow.record(k, v);
+        }
+    }
+
+    pub fn is_secondary(&self) -> bool {
+        self.secondary
+    }
+
+    /// Replays whatever the primary has written since the last call. No-op on
+    /// a primary instance.
+    pub fn catch_up(&self) {
+        if !self.secondary {
+            return;
+        }
+        if let Err(e) = self.db.try_catch_up_with_primary() {
+            log::error!("Error catching up with primary: {}", e);
+        }
+    }
+
+    /// Creates a consistent, hard-linked copy of every column family at `path`,
+    /// which must not exist yet.
+    pub fn checkpoint(&self, path: &Path) -> Result<(), Error> {
+        Checkpoint::new(&self.db)?.create_checkpoint(path)
+    }
+
+    /// Atomically replaces the value of `k` with what `f` returns for the
+    /// current one, `None` meaning delete. Calls to `update` are serialized
+    /// against each other, so `f` sees no concurrent changes made through it.
+    pub fn update<T>(
+        &self,
+        k: &str,
+        f: impl FnOnce(Option<String>) -> (Option<String>, T),
+    ) -> Result<T, Error> {
+        let _guard = self.update_lock.lock().unwrap_or_else(|e| e.into_inner());
+        let current = self
+            .db
+            .get(k.as_bytes())?
+            .map(|v| String::from_utf8(v).unwrap());
+        let (next, result) = f(current.clone());
+        if next != current {
+            match &next {
+                Some(v) => self.db.put(k.as_bytes(), v.as_bytes())?,
+                None => self.db.delete(k.as_bytes())?,
+            }
+            self.mirror(k, next.as_deref());
+        }
+        Ok(result)
+    }
+
+    /// Writes every pair in one atomic batch.
+    pub fn write_batch<'a>(
+        &self,
+        entries: impl Iterator<Item = (&'a str, &'a str)>,
+    ) -> Result<(), Error> {
+        self.apply_batch(entries.map(|(k, v)| (k, Some(v))))
+    }
+
+    /// Like `write_batch`, with `None` deleting the key.
+    pub fn apply_batch<'a>(
+        &self,
+        entries: impl Iterator<Item = (&'a str, Option<&'a str>)>,
+    ) -> Result<(), Error> {
+        let entries: Vec<_> = entries.collect();
+        let mut batch = WriteBatch::default();
+        for (k, v) in &entries {
+            match v {
+                Some(v) => batch.put(k.as_bytes(), v.as_bytes()),
+                None => batch.delete(k.as_bytes()),
+            }
+        }
+        self.ordered(|| {
+            self.db.write(batch)?;
+            for (k, v) in entries {
+                self.mirror(k, v);
+            }
+            Ok(())
+        })
+    }
+
+    /// Reads a RocksDB property such as `rocksdb.levelstats`.
+    pub fn property(&self, name: &str) -> Option<String> {
+        self.db.property_value(name).unwrap_or_else(|e| {
+            log::error!("Error reading property {}: {}", name, e);
+            None
+        })
+    }
+
+    /// Reads an integer RocksDB property such as `rocksdb.num-files-at-level0`.
+    pub fn property_int(&self, name: &str) -> Option<u64> {
+        self.db.property_int_value(name).unwrap_or_else(|e| {
+            log::error!("Error reading property {}: {}", name, e);
+            None
+        })
+    }
+
+    /// Like `find`, but returns the stored bytes as they are.
+    pub fn find_raw(&self, k: &str) -> Option<Vec<u8>> {
+        self.db.get(k.as_bytes()).unwrap_or_else(|e| {
+            log::error!("Error retrieving value for {}: {}", k, e);
+            None
+        })
+    }
+
+    /// Deletes `k` and compacts the range holding it down to the bottommost
+    /// level, so the old value is dropped from the SST files instead of just
+    /// being shadowed by a tombstone. Returns whether the key existed.
+    pub fn purge(&self, k: &str) -> Result<bool, Error> {
+        let existed = self.update(k, |current| (None, current.is_some()))?;
+        self.compact_away(k);
+        Ok(existed)
+    }
+
+    /// Compacts the range holding the deleted key `k` down to the bottommost
+    /// level, dropping its old value from the SST files.
+    pub fn compact_away(&self, k: &str) {
+        let mut opts = CompactOptions::default();
+        opts.set_bottommost_level_compaction(BottommostLevelCompaction::Force);
+        self.db
+            .compact_range_opt(Some(k.as_bytes()), Some(k.as_bytes()), &opts);
+    }
+
+    /// Iterates over every key/value pair in key order.
+    pub fn iter(&self) -> impl Iterator<Item = Result<(Box<[u8]>, Box<[u8]>), Error>> + '_ {
+        self.db.iterator(IteratorMode::Start)
+    }
+
+    /// Iterates over the entries from `start` onwards, in key order.
+    pub fn iter_from<'a>(
+        &'a self,
+        start: &'a str,
+    ) -> impl Iterator<Item = Result<(Box<[u8]>, Box<[u8]>), Error>> + 'a {
+        self.db
+            .iterator(IteratorMode::From(start.as_bytes(), Direction::Forward))
+    }
+
+    /// Iterates over the entries whose key starts with `prefix`, in key order.
+    pub fn scan_prefix<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> impl Iterator<Item = Result<(Box<[u8]>, Box<[u8]>), Error>> + 'a {
+        self.iter_from(prefix).take_while(move |item| {
+            item.as_ref()
+                .map_or(true, |(k, _)| k.starts_with(prefix.as_bytes()))
+        })
+    }
 }
 
 impl KVStore for RocksDB {
     fn init(file_path: &str) -> Self {
         RocksDB {
             db: Arc::new(DB::open_default(file_path).unwrap()),
+            secondary: false,
+            update_lock: Arc::new(Mutex::new(())),
+            shadow: None,
         }
     }
 
     fn save(&self, k: &str, v: &str) -> bool {
-        self.db.put(k.as_bytes(), v.as_bytes()).is_ok()
+        self.ordered(|| {
+            let saved = self.db.put(k.as_bytes(), v.as_bytes()).is_ok();
+            if saved {
+                self.mirror(k, Some(v));
+            }
+            saved
+        })
     }
 
     fn find(&self, k: &str) -> Option<String> {
@@ -39,6 +278,43 @@ impl KVStore for RocksDB {
     }
 
     fn delete(&self, k: &str) -> bool {
-        self.db.delete(k.as_bytes()).is_ok()
+        self.ordered(|| {
+            let deleted = self.db.delete(k.as_bytes()).is_ok();
+            if deleted {
+                self.mirror(k, None);
+            }
+            deleted
+        })
+    }
+}
+
+/// A database in a directory of its own under the system temp dir, removed
+/// again when dropped.
+#[cfg(test)]
+pub struct TempDB {
+    pub db: RocksDB,
+    path: std::path::PathBuf,
+}
+
+#[cfg(test)]
+impl TempDB {
+    pub fn open() -> Self {
+        static NEXT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "smol-kv-test-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+        ));
+        TempDB {
+            db: RocksDB::init(path.to_str().unwrap()),
+            path,
+        }
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDB {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
     }
 }
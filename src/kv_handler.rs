@@ -1,61 +1,192 @@
+use crate::aliases;
+use crate::auth;
+use crate::coalesce::WriteCoalescer;
+use crate::encoding;
 use crate::kv::{KVStore, RocksDB};
+use crate::leases;
+use crate::unique::UniqueFields;
+use crate::upstream::ReadThrough;
+use crate::validation::Validation;
 use rand::{distributions::Alphanumeric, Rng};
 
 use actix_web::{
-    web::{Data, Path},
+    web::{block, Data, Path, Payload, Query},
     HttpRequest, HttpResponse, Responder,
 };
 use bytes::Bytes;
+use serde::Deserialize;
 use serde_json::{json, Value};
 use sha1::{Digest, Sha1};
 
-pub async fn head(key: Path<String>, db: Data<RocksDB>) -> impl Responder {
-    match &db.find(&key.into_inner()) {
-        Some(_) => HttpResponse::Ok().finish(),
-        None => HttpResponse::NotFound().finish(),
+#[derive(Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Consistency {
+    /// On a read replica, catch up with the primary before reading
+    #[default]
+    Latest,
+    /// Serve whatever the replica has already replayed
+    StaleOk,
+}
+
+#[derive(Deserialize)]
+pub struct ReadParams {
+    #[serde(default)]
+    consistency: Consistency,
+    /// JSON pointer to return only part of the stored document
+    pointer: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct WriteParams {
+    /// Delete the key when this lease expires
+    lease: Option<String>,
+}
+
+/// Stores the value directly, or through the write coalescer when enabled.
+/// Keys with unique fields are checked and written on their own.
+async fn store(
+    db: &RocksDB,
+    req: &HttpRequest,
+    key: &str,
+    value: &Value,
+) -> Result<(), HttpResponse> {
+    let unique = req.app_data::<Data<UniqueFields>>();
+    if let Some(unique) = unique.filter(|u| u.covers(key)) {
+        return unique.save(db, key, value);
+    }
+    let saved = match req.app_data::<Data<WriteCoalescer>>() {
+        Some(coalescer) => coalescer.save(key, value.to_string()).await,
+        None => db.save(key, &value.to_string()),
+    };
+    if saved {
+        Ok(())
+    } else {
+        Err(HttpResponse::InternalServerError()
+            .content_type("application/json")
+            .finish())
+    }
+}
+
+/// Looks up `key`, falling back to the key it points to if it is an alias.
+fn find_raw(db: &RocksDB, key: &str) -> Option<Vec<u8>> {
+    db.find_raw(key)
+        .or_else(|| aliases::resolve(db, key).and_then(|target| db.find_raw(&target)))
+}
+
+/// Looks up `key` for a read, catching up with the primary first when asked
+/// to and going through the read-through upstream when one is configured.
+async fn read(
+    db: &RocksDB,
+    key: &str,
+    params: &ReadParams,
+    read_through: &Option<Data<ReadThrough>>,
+) -> Result<Option<Vec<u8>>, HttpResponse> {
+    if params.consistency == Consistency::Latest {
+        db.catch_up();
+    }
+    let found = find_raw(db, key);
+    match read_through {
+        Some(read_through) => read_through.read(db, key, found).await,
+        None => Ok(found),
+    }
+}
+
+pub async fn head(
+    key: Path<String>,
+    db: Data<RocksDB>,
+    params: Query<ReadParams>,
+    read_through: Option<Data<ReadThrough>>,
+) -> HttpResponse {
+    match read(&db, &key.into_inner(), &params, &read_through).await {
+        Ok(Some(_)) => HttpResponse::Ok().finish(),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(res) => res,
     }
 }
 
-pub async fn get(key: Path<String>, db: Data<RocksDB>) -> HttpResponse {
-    match &db.find(&key.into_inner()) {
-        Some(v) => serde_json::from_str(v)
-            .map(|obj: Value| {
-                HttpResponse::Ok()
-                    .content_type("application/json")
-                    .body(obj.to_string())
-            })
-            .unwrap_or(
-                HttpResponse::InternalServerError()
-                    .content_type("application/json")
-                    .finish(),
+pub async fn get(
+    key: Path<String>,
+    db: Data<RocksDB>,
+    params: Query<ReadParams>,
+    read_through: Option<Data<ReadThrough>>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let key = key.into_inner();
+    let found = match read(&db, &key, &params, &read_through).await {
+        Ok(found) => found,
+        Err(res) => return res,
+    };
+    let Some(v) = found else {
+        return HttpResponse::NotFound()
+            .content_type("application/json")
+            .finish();
+    };
+    // Values are validated JSON when written, so plain reads can hand the
+    // stored bytes straight back without parsing them
+    if params.pointer.is_none() && !encoding::accepts_cbor(&req) {
+        return HttpResponse::Ok().content_type("application/json").body(v);
+    }
+    match (serde_json::from_slice::<Value>(&v), &params.pointer) {
+        (Ok(obj), None) => encoding::respond(&req, HttpResponse::Ok(), &obj),
+        (Ok(obj), Some(pointer)) => match obj.pointer(pointer) {
+            Some(fragment) => encoding::respond(&req, HttpResponse::Ok(), fragment),
+            None => HttpResponse::NotFound()
+                .content_type("application/json")
+                .body(
+                json!({ "status": 404, "msg": "Pointer does not match anything in this value" })
+                    .to_string(),
             ),
-        None => HttpResponse::NotFound()
+        },
+        (Err(_), _) => HttpResponse::InternalServerError()
             .content_type("application/json")
             .finish(),
     }
 }
 
-pub async fn post(key: Path<String>, db: Data<RocksDB>, body: Bytes) -> HttpResponse {
-    serde_json::from_slice(&body.slice(..))
-        .map(|obj: Value| {
-            if db.save(&key.into_inner(), &obj.to_string()) {
-                HttpResponse::Ok()
-                    .content_type("application/json")
-                    .body(obj.to_string())
-            } else {
-                HttpResponse::InternalServerError()
-                    .content_type("application/json")
-                    .finish()
+pub async fn post(
+    key: Path<String>,
+    db: Data<RocksDB>,
+    validation: Data<Validation>,
+    params: Query<WriteParams>,
+    payload: Payload,
+    req: HttpRequest,
+) -> HttpResponse {
+    let key = key.into_inner();
+    if let Err(res) = validation.check_key(&key) {
+        return res;
+    }
+    let body = match validation.read_value(&req, payload).await {
+        Ok(body) => body,
+        Err(res) => return res,
+    };
+    match encoding::decode(&req, &body) {
+        Ok(obj) => {
+            // Attach first so a missing lease fails before anything is written
+            let attached = match &params.lease {
+                Some(lease) => match leases::attach(&db, lease, &key) {
+                    Ok(previous) => Some((lease, previous)),
+                    Err(res) => return res,
+                },
+                None => None,
+            };
+            match store(&db, &req, &key, &obj).await {
+                Ok(()) => {
+                    if attached.is_none() {
+                        leases::release(&db, &key);
+                    }
+                    encoding::respond(&req, HttpResponse::Ok(), &obj)
+                }
+                Err(res) => {
+                    // Keep the lease from deleting a value this write never replaced
+                    if let Some((lease, previous)) = attached {
+                        leases::restore(&db, lease, &key, previous);
+                    }
+                    res
+                }
             }
-        })
-        .unwrap_or(
-            HttpResponse::BadRequest()
-                .content_type("application/json")
-                .body(
-                    json!({ "status": 400, "msg": "Parsing failed. value is not in JSON Format"})
-                        .to_string(),
-                ),
-        )
+        }
+        Err(res) => res,
+    }
 }
 
 pub async fn benchmark(
@@ -64,12 +195,7 @@ pub async fn benchmark(
     body: Bytes,
     req: HttpRequest,
 ) -> HttpResponse {
-    let token_header = req
-        .headers()
-        .get("Authorization")
-        .and_then(|hv| hv.to_str().ok());
-
-    if token_header.is_none() || token_header != Some(token.get_ref().as_str()) {
+    if !auth::is_admin(&req, &token) {
         return HttpResponse::Unauthorized().finish();
     }
     // Generate a random string
@@ -115,39 +241,100 @@ pub async fn benchmark(
     }
 }
 
-pub async fn new(db: Data<RocksDB>, body: Bytes) -> impl Responder {
+pub async fn new(
+    db: Data<RocksDB>,
+    validation: Data<Validation>,
+    payload: Payload,
+    req: HttpRequest,
+) -> impl Responder {
+    let body = match validation.read_value(&req, payload).await {
+        Ok(body) => body,
+        Err(res) => return res,
+    };
     let mut hasher = Sha1::new();
     hasher.update(&body);
 
     let result = hasher.finalize();
     let key = format!("{:x}", result);
 
-    serde_json::from_slice(&body.slice(..))
-        .map(|obj: Value| {
-            if db.save(&key, &obj.to_string()) {
-                HttpResponse::Ok()
-                    .content_type("application/json")
-                    .body(json!({ "key": key, "data": obj }).to_string())
-            } else {
-                HttpResponse::InternalServerError()
-                    .content_type("application/json")
-                    .finish()
+    match encoding::decode(&req, &body) {
+        Ok(obj) => match store(&db, &req, &key, &obj).await {
+            Ok(()) => {
+                leases::release(&db, &key);
+                encoding::respond(
+                    &req,
+                    HttpResponse::Ok(),
+                    &json!({ "key": key, "data": obj }),
+                )
             }
-        })
-        .unwrap_or(
-            HttpResponse::BadRequest()
-                .content_type("application/json")
-                .body(
-                    json!({ "status": 400, "msg": "Parsing failed. value is not in JSON Format"})
-                        .to_string(),
-                ),
-        )
+            Err(res) => res,
+        },
+        Err(res) => res,
+    }
 }
-pub async fn delete(key: Path<String>, db: Data<RocksDB>) -> HttpResponse {
-    match &db.delete(&key.into_inner()) {
-        true => HttpResponse::Ok().content_type("application/json").finish(),
+pub async fn delete(key: Path<String>, db: Data<RocksDB>, req: HttpRequest) -> HttpResponse {
+    let key = key.into_inner();
+    let unique = req.app_data::<Data<UniqueFields>>();
+    let deleted = match unique.filter(|u| u.covers(&key)) {
+        Some(unique) => unique
+            .delete(&db, &key)
+            .map_err(|e| log::error!("Error deleting {}: {}", key, e))
+            .is_ok(),
+        None => db.delete(&key),
+    };
+    match deleted {
+        true => {
+            leases::release(&db, &key);
+            HttpResponse::Ok().content_type("application/json").finish()
+        }
         false => HttpResponse::InternalServerError()
             .content_type("application/json")
             .finish(),
     }
 }
+
+/// Deletes the key and compacts it out of the data files. Slower than a
+/// plain delete, meant for data that must really be gone from disk.
+pub async fn purge(
+    key: Path<String>,
+    db: Data<RocksDB>,
+    token: Data<String>,
+    req: HttpRequest,
+) -> HttpResponse {
+    if !auth::is_admin(&req, &token) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let key = key.into_inner();
+    let unique = req
+        .app_data::<Data<UniqueFields>>()
+        .filter(|u| u.covers(&key))
+        .cloned();
+    let purged = {
+        let db = db.clone();
+        let key = key.clone();
+        block(move || {
+            let purged = match unique {
+                Some(unique) => unique.purge(&db, &key),
+                None => db.purge(&key),
+            };
+            leases::release(&db, &key);
+            purged
+        })
+        .await
+    };
+    match purged {
+        Ok(Ok(true)) => HttpResponse::Ok().content_type("application/json").finish(),
+        Ok(Ok(false)) => HttpResponse::NotFound()
+            .content_type("application/json")
+            .finish(),
+        Ok(Err(e)) => {
+            log::error!("Error purging {}: {}", key, e);
+            HttpResponse::InternalServerError()
+                .content_type("application/json")
+                .finish()
+        }
+        Err(_) => HttpResponse::InternalServerError()
+            .content_type("application/json")
+            .finish(),
+    }
+}
@@ -0,0 +1,424 @@
+use crate::clock::{self, now};
+use crate::kv::{self, KVStore, RocksDB};
+
+use actix_web::{
+    web::{Data, Json, Path},
+    HttpResponse,
+};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::time::Duration;
+
+const PREFIX: &str = "_lease/";
+/// Records which lease a key is on, as `_leased/{key}` holding the lease id
+const OWNER_PREFIX: &str = "_leased/";
+
+/// Lease state as stored under `_lease/{id}`, including the keys that get
+/// deleted together with it.
+#[derive(Serialize, Deserialize)]
+struct Lease {
+    ttl: u64,
+    expires_at: u64,
+    keys: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct Grant {
+    /// Lease length in milliseconds, renewed by every heartbeat
+    ttl: u64,
+}
+
+fn lease_key(id: &str) -> String {
+    format!("{PREFIX}{id}")
+}
+
+fn owner_key(key: &str) -> String {
+    format!("{OWNER_PREFIX}{key}")
+}
+
+fn parse(value: Option<&str>, now: u64) -> Option<Lease> {
+    value
+        .and_then(|v| serde_json::from_str::<Lease>(v).ok())
+        .filter(|lease| lease.expires_at > now)
+}
+
+fn lease_json(id: &str, lease: &Lease) -> String {
+    json!({
+        "id": id,
+        "ttl": lease.ttl,
+        "expires_at": lease.expires_at,
+        "keys": lease.keys,
+    })
+    .to_string()
+}
+
+fn not_found() -> HttpResponse {
+    HttpResponse::NotFound()
+        .content_type("application/json")
+        .body(json!({ "status": 404, "msg": "Lease not found or expired" }).to_string())
+}
+
+fn internal_error(id: &str, e: kv::Error) -> HttpResponse {
+    log::error!("Error updating lease {}: {}", id, e);
+    HttpResponse::InternalServerError()
+        .content_type("application/json")
+        .finish()
+}
+
+/// Adds `key` to the keys of lease `id`. Fails with 404 if the lease is gone.
+fn add_key(db: &RocksDB, id: &str, key: &str) -> Result<(), HttpResponse> {
+    let added = db
+        .update(&lease_key(id), |current| {
+            match parse(current.as_deref(), now()) {
+                Some(mut lease) => {
+                    if !lease.keys.iter().any(|k| k == key) {
+                        lease.keys.push(key.to_string());
+                    }
+                    (Some(serde_json::to_string(&lease).unwrap()), true)
+                }
+                None => (current, false),
+            }
+        })
+        .map_err(|e| internal_error(id, e))?;
+    added.then_some(()).ok_or_else(not_found)
+}
+
+/// Removes `key` from the keys of lease `id`, leaving the value in place.
+fn remove_key(db: &RocksDB, id: &str, key: &str) {
+    let removed = db.update(&lease_key(id), |current| {
+        // Expired leases the sweeper hasn't reached yet still own their keys
+        match current.as_deref().map(serde_json::from_str::<Lease>) {
+            Some(Ok(mut lease)) => {
+                lease.keys.retain(|k| k != key);
+                (Some(serde_json::to_string(&lease).unwrap()), ())
+            }
+            _ => (current, ()),
+        }
+    });
+    if let Err(e) = removed {
+        log::error!("Error detaching {} from lease {}: {}", key, id, e);
+    }
+}
+
+/// Points the record of which lease `key` is on at `id`, `None` meaning no
+/// lease. Returns the lease it pointed at before.
+fn set_owner(db: &RocksDB, key: &str, id: Option<&str>) -> Result<Option<String>, kv::Error> {
+    db.update(&owner_key(key), |current| (id.map(String::from), current))
+}
+
+/// Records `key` as belonging to lease `id`, so it is deleted when the lease
+/// expires, and takes it off the lease it was on before. Fails with 404 if the
+/// lease is gone. Returns the lease the key was on before, so a failed write
+/// can [`restore`] it.
+pub fn attach(db: &RocksDB, id: &str, key: &str) -> Result<Option<String>, HttpResponse> {
+    add_key(db, id, key)?;
+    let previous = set_owner(db, key, Some(id)).map_err(|e| internal_error(id, e))?;
+    if let Some(previous) = previous.as_deref().filter(|p| *p != id) {
+        remove_key(db, previous, key);
+    }
+    Ok(previous)
+}
+
+/// Undoes [`attach`] for a write that failed, moving `key` from lease `id`
+/// back to the lease it was on before, if that one is still around.
+pub fn restore(db: &RocksDB, id: &str, key: &str, previous: Option<String>) {
+    if previous.as_deref() == Some(id) {
+        return;
+    }
+    remove_key(db, id, key);
+    let previous = previous.filter(|p| add_key(db, p, key).is_ok());
+    if let Err(e) = set_owner(db, key, previous.as_deref()) {
+        log::error!("Error restoring the lease of {}: {}", key, e);
+    }
+}
+
+/// Takes `key` off the lease it is on, if any, once it was written without a
+/// lease or deleted, so the lease won't delete whatever the key holds later.
+pub fn release(db: &RocksDB, key: &str) {
+    // Most keys are on no lease, so look before taking the update lock
+    if db.find(&owner_key(key)).is_none() {
+        return;
+    }
+    match set_owner(db, key, None) {
+        Ok(Some(id)) => remove_key(db, &id, key),
+        Ok(None) => {}
+        Err(e) => log::error!("Error releasing {} from its lease: {}", key, e),
+    }
+}
+
+/// Deletes the keys of lease `id`, which has ended, along with the records
+/// pointing them at it.
+fn delete_keys(db: &RocksDB, id: &str, keys: &[String]) {
+    for key in keys {
+        db.delete(key);
+        let cleared = db.update(&owner_key(key), |current| match current {
+            Some(owner) if owner == id => (None, ()),
+            other => (other, ()),
+        });
+        if let Err(e) = cleared {
+            log::error!("Error clearing the lease of {}: {}", key, e);
+        }
+    }
+}
+
+/// Deletes every expired lease along with its keys.
+fn sweep(db: &RocksDB) {
+    let now = now();
+    let expired: Vec<String> = db
+        .scan_prefix(PREFIX)
+        .filter_map(Result::ok)
+        .filter_map(|(k, v)| {
+            let lease: Lease = serde_json::from_slice(&v).ok()?;
+            (lease.expires_at <= now).then(|| String::from_utf8_lossy(&k).into_owned())
+        })
+        .collect();
+
+    for record in expired {
+        // Re-check under the update lock in case a heartbeat landed meanwhile
+        let keys = db.update(&record, |current| match current {
+            Some(v) => match serde_json::from_str::<Lease>(&v) {
+                Ok(lease) if lease.expires_at <= now => (None, lease.keys),
+                _ => (Some(v), Vec::new()),
+            },
+            None => (None, Vec::new()),
+        });
+        match keys {
+            Ok(keys) => {
+                delete_keys(db, &record[PREFIX.len()..], &keys);
+                log::info!("Lease {} expired, deleted {} keys", record, keys.len());
+            }
+            Err(e) => log::error!("Error expiring lease {}: {}", record, e),
+        }
+    }
+}
+
+pub fn spawn_sweeper(db: RocksDB, interval: Duration) {
+    std::thread::spawn(move || loop {
+        std::thread::sleep(interval);
+        sweep(&db);
+    });
+}
+
+pub async fn grant(db: Data<RocksDB>, body: Json<Grant>) -> HttpResponse {
+    if let Err(res) = clock::check_ttl(body.ttl) {
+        return res;
+    }
+    let id: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(16)
+        .map(char::from)
+        .collect();
+    let lease = Lease {
+        ttl: body.ttl,
+        expires_at: now() + body.ttl,
+        keys: Vec::new(),
+    };
+
+    if db.save(&lease_key(&id), &serde_json::to_string(&lease).unwrap()) {
+        HttpResponse::Ok()
+            .content_type("application/json")
+            .body(lease_json(&id, &lease))
+    } else {
+        HttpResponse::InternalServerError()
+            .content_type("application/json")
+            .finish()
+    }
+}
+
+/// Keeps the lease alive for another `ttl` milliseconds.
+pub async fn heartbeat(id: Path<String>, db: Data<RocksDB>) -> HttpResponse {
+    let result = db.update(&lease_key(&id), |current| {
+        let now = now();
+        match parse(current.as_deref(), now) {
+            Some(mut lease) => {
+                lease.expires_at = now + lease.ttl;
+                let body = lease_json(&id, &lease);
+                (Some(serde_json::to_string(&lease).unwrap()), Some(body))
+            }
+            None => (current, None),
+        }
+    });
+    match result {
+        Ok(Some(body)) => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(body),
+        Ok(None) => not_found(),
+        Err(e) => internal_error(&id, e),
+    }
+}
+
+pub async fn get(id: Path<String>, db: Data<RocksDB>) -> HttpResponse {
+    match parse(db.find(&lease_key(&id)).as_deref(), now()) {
+        Some(lease) => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(lease_json(&id, &lease)),
+        None => not_found(),
+    }
+}
+
+/// Ends the lease right away, deleting its keys.
+pub async fn revoke(id: Path<String>, db: Data<RocksDB>) -> HttpResponse {
+    let result = db.update(&lease_key(&id), |current| {
+        // Expired leases the sweeper hasn't reached yet still own their keys
+        let lease = current
+            .as_deref()
+            .and_then(|v| serde_json::from_str::<Lease>(v).ok());
+        (None, lease.map(|lease| lease.keys))
+    });
+    match result {
+        Ok(Some(keys)) => {
+            delete_keys(&db, &id, &keys);
+            HttpResponse::Ok().content_type("application/json").finish()
+        }
+        Ok(None) => not_found(),
+        Err(e) => internal_error(&id, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv::TempDB;
+    use actix_web::{body::to_bytes, http::StatusCode};
+    use serde_json::Value;
+
+    async fn json_body(res: HttpResponse) -> Value {
+        serde_json::from_slice(&to_bytes(res.into_body()).await.unwrap()).unwrap()
+    }
+
+    /// Grants a lease of `ttl` milliseconds and returns its id.
+    async fn granted(db: &Data<RocksDB>, ttl: u64) -> String {
+        let res = grant(db.clone(), Json(Grant { ttl })).await;
+        json_body(res).await["id"].as_str().unwrap().to_string()
+    }
+
+    #[actix_web::test]
+    async fn expired_leases_take_their_keys_along() {
+        let tmp = TempDB::open();
+        let db = Data::new(tmp.db.clone());
+        let id = granted(&db, 100).await;
+        db.save("kept", "1");
+        db.save("leased", "1");
+        assert!(attach(&db, &id, "leased").is_ok());
+
+        std::thread::sleep(Duration::from_millis(150));
+        assert_eq!(
+            attach(&db, &id, "kept").unwrap_err().status(),
+            StatusCode::NOT_FOUND
+        );
+        sweep(&db);
+        assert!(db.find("leased").is_none());
+        assert!(db.find("kept").is_some());
+        let res = get(Path::from(id), db.clone()).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn heartbeats_keep_leases_alive() {
+        let tmp = TempDB::open();
+        let db = Data::new(tmp.db.clone());
+        let id = granted(&db, 200).await;
+        db.save("leased", "1");
+        assert!(attach(&db, &id, "leased").is_ok());
+
+        std::thread::sleep(Duration::from_millis(120));
+        let res = heartbeat(Path::from(id.clone()), db.clone()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        std::thread::sleep(Duration::from_millis(120));
+        sweep(&db);
+        assert!(db.find("leased").is_some());
+        let lease = json_body(get(Path::from(id), db.clone()).await).await;
+        assert_eq!(lease["keys"], json!(["leased"]));
+    }
+
+    #[actix_web::test]
+    async fn revoking_deletes_the_keys() {
+        let tmp = TempDB::open();
+        let db = Data::new(tmp.db.clone());
+        let id = granted(&db, 60000).await;
+        db.save("leased", "1");
+        assert!(attach(&db, &id, "leased").is_ok());
+
+        let res = revoke(Path::from(id.clone()), db.clone()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(db.find("leased").is_none());
+        let res = revoke(Path::from(id), db.clone()).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn rejects_ttls_past_the_limit() {
+        let tmp = TempDB::open();
+        let db = Data::new(tmp.db.clone());
+        for ttl in [clock::MAX_TTL + 1, u64::MAX] {
+            let res = grant(db.clone(), Json(Grant { ttl })).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[actix_web::test]
+    async fn restored_keys_outlive_the_lease() {
+        let tmp = TempDB::open();
+        let db = Data::new(tmp.db.clone());
+        let id = granted(&db, 100).await;
+        for key in ["leased", "restored", "failed"] {
+            db.save(key, "1");
+        }
+        assert_eq!(attach(&db, &id, "leased").unwrap(), None);
+        assert_eq!(attach(&db, &id, "leased").unwrap(), Some(id.clone()));
+        assert_eq!(attach(&db, &id, "restored").unwrap(), None);
+        restore(&db, &id, "restored", None);
+        assert_eq!(attach(&db, &id, "failed").unwrap(), None);
+
+        std::thread::sleep(Duration::from_millis(150));
+        // Expired but not swept yet, so the lease still owns its keys
+        restore(&db, &id, "failed", None);
+
+        sweep(&db);
+        assert!(db.find("leased").is_none());
+        assert!(db.find("restored").is_some());
+        assert!(db.find("failed").is_some());
+        for key in ["leased", "restored", "failed"] {
+            assert!(db.find(&owner_key(key)).is_none(), "{key}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn keys_written_without_a_lease_leave_theirs() {
+        let tmp = TempDB::open();
+        let db = Data::new(tmp.db.clone());
+        let id = granted(&db, 100).await;
+        db.save("rewritten", "1");
+        assert!(attach(&db, &id, "rewritten").is_ok());
+        db.save("rewritten", "2");
+        release(&db, "rewritten");
+
+        std::thread::sleep(Duration::from_millis(150));
+        sweep(&db);
+        assert_eq!(db.find("rewritten").as_deref(), Some("2"));
+    }
+
+    #[actix_web::test]
+    async fn keys_move_to_the_lease_they_were_last_written_with() {
+        let tmp = TempDB::open();
+        let db = Data::new(tmp.db.clone());
+        let (first, second) = (granted(&db, 60000).await, granted(&db, 60000).await);
+        db.save("moved", "1");
+        assert_eq!(attach(&db, &first, "moved").unwrap(), None);
+        assert_eq!(attach(&db, &second, "moved").unwrap(), Some(first.clone()));
+        let lease = json_body(get(Path::from(first.clone()), db.clone()).await).await;
+        assert_eq!(lease["keys"], json!([]));
+
+        // A failed write puts the key back on the lease it was on
+        restore(&db, &second, "moved", Some(first.clone()));
+        let lease = json_body(get(Path::from(first.clone()), db.clone()).await).await;
+        assert_eq!(lease["keys"], json!(["moved"]));
+
+        assert!(attach(&db, &second, "moved").is_ok());
+        revoke(Path::from(first), db.clone()).await;
+        assert!(db.find("moved").is_some());
+        revoke(Path::from(second), db.clone()).await;
+        assert!(db.find("moved").is_none());
+    }
+}
@@ -0,0 +1,308 @@
+use crate::clock::{self, now};
+use crate::kv::{KVStore, RocksDB};
+use crate::validation::Validation;
+
+use actix_web::{
+    web::{Data, Json, Path},
+    HttpResponse,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+/// Lock state as stored under `_lock/{name}`. Released locks are kept with no
+/// owner so fencing tokens keep increasing across holders.
+#[derive(Serialize, Deserialize)]
+struct Lock {
+    owner: Option<String>,
+    token: u64,
+    expires_at: u64,
+}
+
+impl Lock {
+    fn held_by(&self, owner: &str, token: u64, now: u64) -> bool {
+        self.owner.as_deref() == Some(owner) && self.token == token && self.expires_at > now
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Acquire {
+    owner: String,
+    /// Lease length in milliseconds
+    ttl: u64,
+}
+
+#[derive(Deserialize)]
+pub struct Held {
+    owner: String,
+    token: u64,
+    /// New lease length in milliseconds when renewing
+    ttl: Option<u64>,
+}
+
+enum Outcome {
+    Granted(Lock),
+    Conflict(Lock),
+    NotHeld,
+}
+
+fn lock_key(name: &str) -> String {
+    format!("_lock/{name}")
+}
+
+/// Runs `f` against the current lock state atomically and stores the lock it
+/// grants, if any.
+fn transition(
+    db: &RocksDB,
+    name: &str,
+    f: impl FnOnce(Option<Lock>, u64) -> Outcome,
+) -> Result<Outcome, HttpResponse> {
+    db.update(&lock_key(name), |current| {
+        let lock = current
+            .as_deref()
+            .and_then(|v| serde_json::from_str(v).ok());
+        let outcome = f(lock, now());
+        let next = match &outcome {
+            Outcome::Granted(lock) => Some(serde_json::to_string(lock).unwrap()),
+            _ => current,
+        };
+        (next, outcome)
+    })
+    .map_err(|e| {
+        log::error!("Error updating lock {}: {}", name, e);
+        HttpResponse::InternalServerError()
+            .content_type("application/json")
+            .finish()
+    })
+}
+
+fn respond(name: &str, outcome: Outcome) -> HttpResponse {
+    match outcome {
+        Outcome::Granted(lock) => HttpResponse::Ok().content_type("application/json").body(
+            json!({
+                "name": name,
+                "owner": lock.owner,
+                "token": lock.token,
+                "expires_at": lock.expires_at,
+            })
+            .to_string(),
+        ),
+        Outcome::Conflict(lock) => HttpResponse::Conflict()
+            .content_type("application/json")
+            .body(
+                json!({
+                    "status": 409,
+                    "msg": "Lock is held by another owner",
+                    "owner": lock.owner,
+                    "expires_at": lock.expires_at,
+                })
+                .to_string(),
+            ),
+        Outcome::NotHeld => HttpResponse::Conflict()
+            .content_type("application/json")
+            .body(
+                json!({ "status": 409, "msg": "Lock is not held with this owner and token" })
+                    .to_string(),
+            ),
+    }
+}
+
+/// Takes the lock if it is free or its lease expired, handing out the next
+/// fencing token.
+pub async fn acquire(
+    name: Path<String>,
+    db: Data<RocksDB>,
+    validation: Data<Validation>,
+    body: Json<Acquire>,
+) -> HttpResponse {
+    let name = name.into_inner();
+    let Acquire { owner, ttl } = body.into_inner();
+    if let Err(res) = validation
+        .check_key(&name)
+        .and_then(|_| clock::check_ttl(ttl))
+    {
+        return res;
+    }
+
+    let outcome = transition(&db, &name, |lock, now| match lock {
+        Some(lock) if lock.owner.is_some() && lock.expires_at > now => Outcome::Conflict(lock),
+        lock => Outcome::Granted(Lock {
+            owner: Some(owner),
+            token: lock.map_or(1, |l| l.token + 1),
+            expires_at: now + ttl,
+        }),
+    });
+    match outcome {
+        Ok(outcome) => respond(&name, outcome),
+        Err(res) => res,
+    }
+}
+
+/// Extends the lease of a lock the caller still holds.
+pub async fn renew(name: Path<String>, db: Data<RocksDB>, body: Json<Held>) -> HttpResponse {
+    let name = name.into_inner();
+    let Held { owner, token, ttl } = body.into_inner();
+    if let Err(res) = ttl.map_or(Ok(()), clock::check_ttl) {
+        return res;
+    }
+
+    let outcome = transition(&db, &name, |lock, now| match lock {
+        Some(lock) if lock.held_by(&owner, token, now) => {
+            let ttl = ttl.unwrap_or(lock.expires_at - now);
+            Outcome::Granted(Lock {
+                expires_at: now + ttl,
+                ..lock
+            })
+        }
+        _ => Outcome::NotHeld,
+    });
+    match outcome {
+        Ok(outcome) => respond(&name, outcome),
+        Err(res) => res,
+    }
+}
+
+pub async fn release(name: Path<String>, db: Data<RocksDB>, body: Json<Held>) -> HttpResponse {
+    let name = name.into_inner();
+    let Held { owner, token, .. } = body.into_inner();
+
+    let outcome = transition(&db, &name, |lock, now| match lock {
+        Some(lock) if lock.held_by(&owner, token, now) => Outcome::Granted(Lock {
+            owner: None,
+            expires_at: now,
+            ..lock
+        }),
+        _ => Outcome::NotHeld,
+    });
+    match outcome {
+        Ok(outcome) => respond(&name, outcome),
+        Err(res) => res,
+    }
+}
+
+pub async fn get(name: Path<String>, db: Data<RocksDB>) -> HttpResponse {
+    let name = name.into_inner();
+    let lock = db
+        .find(&lock_key(&name))
+        .and_then(|v| serde_json::from_str::<Lock>(&v).ok())
+        .filter(|lock| lock.owner.is_some() && lock.expires_at > now());
+    match lock {
+        Some(lock) => respond(&name, Outcome::Granted(lock)),
+        None => HttpResponse::NotFound()
+            .content_type("application/json")
+            .finish(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv::TempDB;
+    use actix_web::{body::to_bytes, http::StatusCode};
+    use serde_json::Value;
+
+    fn name() -> Path<String> {
+        Path::from("job".to_string())
+    }
+
+    fn acquiring(owner: &str, ttl: u64) -> Json<Acquire> {
+        Json(Acquire {
+            owner: owner.to_string(),
+            ttl,
+        })
+    }
+
+    fn held(owner: &str, token: u64, ttl: Option<u64>) -> Json<Held> {
+        Json(Held {
+            owner: owner.to_string(),
+            token,
+            ttl,
+        })
+    }
+
+    async fn json_body(res: HttpResponse) -> Value {
+        serde_json::from_slice(&to_bytes(res.into_body()).await.unwrap()).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn hands_out_increasing_tokens() {
+        let tmp = TempDB::open();
+        let db = Data::new(tmp.db.clone());
+        let validation = Data::new(Validation::from_env());
+
+        let res = acquire(
+            name(),
+            db.clone(),
+            validation.clone(),
+            acquiring("a", 60000),
+        )
+        .await;
+        assert_eq!(json_body(res).await["token"], 1);
+        let res = acquire(
+            name(),
+            db.clone(),
+            validation.clone(),
+            acquiring("b", 60000),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+
+        let res = release(name(), db.clone(), held("a", 1, None)).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = acquire(
+            name(),
+            db.clone(),
+            validation.clone(),
+            acquiring("b", 60000),
+        )
+        .await;
+        let lock = json_body(res).await;
+        assert_eq!(lock["owner"], "b");
+        assert_eq!(lock["token"], 2);
+    }
+
+    #[actix_web::test]
+    async fn only_the_holder_can_renew() {
+        let tmp = TempDB::open();
+        let db = Data::new(tmp.db.clone());
+        let validation = Data::new(Validation::from_env());
+        acquire(name(), db.clone(), validation, acquiring("a", 60000)).await;
+
+        let res = renew(name(), db.clone(), held("b", 1, None)).await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        let res = renew(name(), db.clone(), held("a", 2, None)).await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        let res = renew(name(), db.clone(), held("a", 1, Some(120000))).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = get(name(), db.clone()).await;
+        assert_eq!(json_body(res).await["owner"], "a");
+    }
+
+    #[actix_web::test]
+    async fn expired_locks_can_be_taken_over() {
+        let tmp = TempDB::open();
+        let db = Data::new(tmp.db.clone());
+        let validation = Data::new(Validation::from_env());
+        acquire(name(), db.clone(), validation.clone(), acquiring("a", 1)).await;
+        std::thread::sleep(std::time::Duration::from_millis(5));
+
+        let res = get(name(), db.clone()).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let res = acquire(name(), db.clone(), validation, acquiring("b", 60000)).await;
+        assert_eq!(json_body(res).await["token"], 2);
+    }
+
+    #[actix_web::test]
+    async fn rejects_ttls_past_the_limit() {
+        let tmp = TempDB::open();
+        let db = Data::new(tmp.db.clone());
+        let validation = Data::new(Validation::from_env());
+        for ttl in [clock::MAX_TTL + 1, u64::MAX] {
+            let res = acquire(name(), db.clone(), validation.clone(), acquiring("a", ttl)).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        }
+
+        acquire(name(), db.clone(), validation, acquiring("a", 60000)).await;
+        let res = renew(name(), db.clone(), held("a", 1, Some(u64::MAX))).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,139 @@
+use crate::auth;
+use crate::kv::RocksDB;
+use crate::stalls::StallCounters;
+
+use actix_web::{web::Data, HttpRequest, HttpResponse};
+use serde_json::{json, Value};
+use std::{collections::HashMap, sync::atomic::Ordering};
+
+/// Levels RocksDB uses with default options.
+const LEVELS: usize = 7;
+
+/// Per level size in MB from the `rocksdb.levelstats` table.
+fn level_sizes(levelstats: &str) -> HashMap<usize, f64> {
+    levelstats
+        .lines()
+        .filter_map(|line| {
+            let mut cols = line.split_whitespace();
+            let level = cols.next()?.parse().ok()?;
+            let size = cols.nth(1)?.parse().ok()?;
+            Some((level, size))
+        })
+        .collect()
+}
+
+/// Score, compaction count and seconds spent compacting per level, from the
+/// compaction stats table in `rocksdb.cfstats-no-file-histogram`, e.g.
+/// `  L1  4/0  10.21 MB  0.9  1.2 ... Comp(sec) CompMergeCPU(sec) Comp(cnt) ...`
+fn compaction_stats(cfstats: &str) -> HashMap<usize, (f64, f64, u64)> {
+    cfstats
+        .lines()
+        .filter_map(|line| {
+            let cols: Vec<&str> = line.split_whitespace().collect();
+            let level = cols.first()?.strip_prefix('L')?.parse().ok()?;
+            // Size takes two columns: value and unit
+            let score = cols.get(4)?.parse().ok()?;
+            let seconds = cols.get(14)?.parse().ok()?;
+            let count = cols.get(16)?.parse().ok()?;
+            Some((level, (score, seconds, count)))
+        })
+        .collect()
+}
+
+/// Shape of the LSM tree: files, size, compaction score and compaction
+/// history per level, plus what is waiting to be compacted.
+pub async fn stats(
+    db: Data<RocksDB>,
+    stalls: Option<Data<StallCounters>>,
+    token: Data<String>,
+    req: HttpRequest,
+) -> HttpResponse {
+    if !auth::is_admin(&req, &token) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let sizes = level_sizes(&db.property("rocksdb.levelstats").unwrap_or_default());
+    let cfstats = db
+        .property("rocksdb.cfstats-no-file-histogram")
+        .unwrap_or_default();
+    let compactions = compaction_stats(&cfstats);
+
+    let levels: Vec<Value> = (0..LEVELS)
+        .map(|level| {
+            let compaction = compactions.get(&level);
+            json!({
+                "level": level,
+                "files": db.property_int(&format!("rocksdb.num-files-at-level{level}")),
+                "size_mb": sizes.get(&level),
+                "compression_ratio": db
+                    .property(&format!("rocksdb.compression-ratio-at-level{level}"))
+                    .and_then(|r| r.parse::<f64>().ok())
+                    .filter(|r| *r >= 0.0),
+                "score": compaction.map(|c| c.0),
+                "compactions": compaction.map(|c| c.2),
+                "compaction_seconds": compaction.map(|c| c.1),
+            })
+        })
+        .collect();
+    // Replicas don't run the stall monitor
+    let write_stalls = stalls.map(|s| {
+        json!({
+            "delayed": s.delayed.load(Ordering::Relaxed),
+            "stopped": s.stopped.load(Ordering::Relaxed),
+        })
+    });
+
+    HttpResponse::Ok().content_type("application/json").body(
+        json!({
+            "levels": levels,
+            "total_sst_bytes": db.property_int("rocksdb.total-sst-files-size"),
+            "live_sst_bytes": db.property_int("rocksdb.live-sst-files-size"),
+            "pending_compaction_bytes": db.property_int("rocksdb.estimate-pending-compaction-bytes"),
+            "compaction_pending": db.property_int("rocksdb.compaction-pending").map(|p| p > 0),
+            "running_compactions": db.property_int("rocksdb.num-running-compactions"),
+            "write_stalls": write_stalls,
+            "cfstats": cfstats,
+        })
+        .to_string(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_level_sizes() {
+        let levelstats = "Level Files Size(MB)\n\
+                          --------------------\n\
+                          \x20 0        2       12\n\
+                          \x20 1        4       10\n\
+                          \x20 2        0        0\n";
+        let sizes = level_sizes(levelstats);
+        assert_eq!(sizes.len(), 3);
+        assert_eq!(sizes[&0], 12.0);
+        assert_eq!(sizes[&1], 10.0);
+        assert_eq!(sizes[&2], 0.0);
+    }
+
+    #[test]
+    fn parses_compaction_stats() {
+        let cfstats = "\n** Compaction Stats [default] **\n\
+            Level    Files   Size     Score Read(GB)  Rn(GB) Rnp1(GB) Write(GB) Wnew(GB) Moved(GB) W-Amp Rd(MB/s) Wr(MB/s) Comp(sec) CompMergeCPU(sec) Comp(cnt) Avg(sec) KeyIn KeyDrop Rblob(GB) Wblob(GB)\n\
+            ----------------------------------------------------------------------------------------------------\n\
+            \x20 L0      2/0   12.00 MB   0.5      0.0     0.0      0.0       0.0      0.0       0.0   1.0      0.0     35.3      0.02              0.01         3    0.007       0      0       0.0       0.0\n\
+            \x20 L1      4/0   10.21 MB   0.9      1.2     0.6      0.6       1.1      0.5       0.0   1.8     50.1     45.0      1.50              1.20         7    0.214     10K    1K       0.0       0.0\n\
+            \x20Sum      6/0   22.21 MB   0.0      1.2     0.6      0.6       1.1      0.5       0.0   1.8     50.1     45.0      1.52              1.21        10    0.152     10K    1K       0.0       0.0\n\
+            \x20Int      0/0    0.00 KB   0.0      0.0     0.0      0.0       0.0      0.0       0.0   0.0      0.0      0.0      0.00              0.00         0    0.000       0      0       0.0       0.0\n";
+        let stats = compaction_stats(cfstats);
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[&0], (0.5, 0.02, 3));
+        assert_eq!(stats[&1], (0.9, 1.5, 7));
+    }
+
+    #[test]
+    fn ignores_unparseable_lines() {
+        assert!(level_sizes("").is_empty());
+        assert!(compaction_stats("L0 garbage").is_empty());
+        assert!(compaction_stats("Uptime(secs): 10.0 total").is_empty());
+    }
+}
@@ -1,13 +1,45 @@
+mod aliases;
+mod auth;
+mod backup;
+mod clock;
+mod coalesce;
+mod encoding;
+mod events;
+mod export;
+mod fields;
+mod fixtures;
+mod import;
+mod info;
 mod kv;
 mod kv_handler;
+mod leases;
+mod locks;
+mod lsm;
+#[cfg(feature = "memory")]
+mod memory;
+mod migrate;
+mod queue;
+mod sequences;
+mod shadow;
+mod stalls;
+mod unique;
+mod upstream;
+mod validation;
+mod watchdog;
+
+use actix_web::web::{delete, get, head, post, put, resource, scope, JsonConfig, ServiceConfig};
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     use actix_web::{
+        dev::Service,
+        error::InternalError,
+        http::{Method, StatusCode},
         middleware::Logger,
-        web::{delete, get, head, post, resource, scope, Data, JsonConfig, PayloadConfig},
-        App, HttpServer,
+        web::{Data, JsonConfig, PayloadConfig},
+        App, HttpResponse, HttpServer,
     };
+    use serde_json::json;
 
     let port = std::env::var("PORT")
         .unwrap_or("5050".to_string())
@@ -18,37 +50,547 @@ async fn main() -> std::io::Result<()> {
         .parse::<usize>()
         .unwrap();
     let token = std::env::var("ADMIN_TOKEN").unwrap_or("supersecret".to_string());
-    let db_path = std::env::var("DATABABASE_PATH").unwrap_or("./rocksdb".to_string());
+    // Older releases only read the misspelled name
+    let db_path = std::env::var("DATABASE_PATH")
+        .or_else(|_| std::env::var("DATABABASE_PATH"))
+        .unwrap_or("./rocksdb".to_string());
     let log_level = std::env::var("LOG_LEVEL").unwrap_or("info".to_string());
-    let db: kv::RocksDB = kv::KVStore::init(&db_path);
+    let secondary_path = std::env::var("SECONDARY_PATH").ok();
+    let sync_interval = std::env::var("SECONDARY_SYNC_INTERVAL_MS")
+        .unwrap_or("1000".to_string())
+        .parse::<u64>()
+        .unwrap();
+    let memory_budget = std::env::var("ROCKSDB_MEMORY_BUDGET")
+        .unwrap_or("0".to_string())
+        .parse::<usize>()
+        .unwrap();
+    let db: kv::RocksDB = match &secondary_path {
+        Some(path) => kv::RocksDB::init_secondary(&db_path, path),
+        None if memory_budget > 0 => kv::RocksDB::init_bounded(&db_path, memory_budget),
+        None => kv::KVStore::init(&db_path),
+    };
+    let read_only = db.is_secondary();
+    let lease_sweep_interval = std::env::var("LEASE_SWEEP_INTERVAL_MS")
+        .unwrap_or("1000".to_string())
+        .parse::<u64>()
+        .unwrap();
+    let stall_check_interval = std::env::var("STALL_CHECK_INTERVAL_MS")
+        .unwrap_or("1000".to_string())
+        .parse::<u64>()
+        .unwrap();
+    let request_timeout = std::env::var("REQUEST_TIMEOUT_MS")
+        .unwrap_or("0".to_string())
+        .parse::<u64>()
+        .unwrap();
+    let write_coalesce = std::env::var("WRITE_COALESCE_MS")
+        .unwrap_or("0".to_string())
+        .parse::<u64>()
+        .unwrap();
+    let validation = validation::Validation::from_env();
+    let limits = validation::PayloadLimits::from_env();
+    let backup_config = backup::BackupConfig::from_env();
+    let shadow = (!read_only)
+        .then(|| shadow::Shadow::from_env(&validation))
+        .flatten();
+    let unique = (!read_only)
+        .then(|| unique::UniqueFields::from_env(&validation))
+        .flatten()
+        .map(Data::new);
+    let read_through = (!read_only)
+        .then(|| upstream::ReadThrough::from_env(&validation))
+        .flatten()
+        .map(Data::new);
+    let db = match &shadow {
+        Some(shadow) => db.with_shadow(shadow.clone()),
+        None => db,
+    };
+    // Shared by all workers so pushes wake pops running anywhere
+    let queue_events = Data::new(queue::QueueEvents::default());
+    let migrator = Data::new(migrate::Migrator::default());
+    let events = Data::new(events::Events::default());
+    let server_info = Data::new(info::ServerInfo {
+        started: std::time::Instant::now(),
+        workers,
+        db_path: db_path.clone(),
+        read_only,
+    });
     std::env::set_var(
         "RUST_LOG",
         format!("{0},actix_web={0},actix_server={0}", log_level),
     );
     env_logger::init();
+    let stall_counters = if read_only {
+        log::info!("serving {db_path} as a read-only replica");
+        let db = db.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(std::time::Duration::from_millis(sync_interval));
+            db.catch_up();
+        });
+        None
+    } else {
+        leases::spawn_sweeper(
+            db.clone(),
+            std::time::Duration::from_millis(lease_sweep_interval),
+        );
+        Some(Data::from(stalls::spawn_monitor(
+            db.clone(),
+            events.clone(),
+            std::time::Duration::from_millis(stall_check_interval),
+        )))
+    };
+    let watchdog = (!read_only)
+        .then(|| watchdog::DiskWatchdog::from_env(&db_path, events.clone()))
+        .flatten();
+    // Shared by all workers so their writes land in the same batches
+    let coalescer = (write_coalesce > 0 && !read_only).then(|| {
+        Data::new(coalesce::WriteCoalescer::spawn(
+            db.clone(),
+            std::time::Duration::from_millis(write_coalesce),
+        ))
+    });
     log::info!("starting HTTP server at http://0.0.0.0:{port}");
     HttpServer::new(move || {
         App::new()
             .app_data(Data::new(db.clone()))
             .app_data(Data::new(token.clone()))
-            .app_data(JsonConfig::default().limit(1024 * 1024 * 50)) // 50 MB
-            .app_data(PayloadConfig::new(1024 * 1024 * 50))
+            .app_data(Data::new(validation.clone()))
+            .app_data(Data::new(backup_config.clone()))
+            .app_data(queue_events.clone())
+            .app_data(migrator.clone())
+            .app_data(events.clone())
+            .app_data(server_info.clone())
+            .configure(|cfg| {
+                if let Some(coalescer) = &coalescer {
+                    cfg.app_data(coalescer.clone());
+                }
+                if let Some(shadow) = &shadow {
+                    cfg.app_data(Data::from(shadow.clone()));
+                }
+                if let Some(unique) = &unique {
+                    cfg.app_data(unique.clone());
+                }
+                if let Some(read_through) = &read_through {
+                    cfg.app_data(read_through.clone());
+                }
+                if let Some(stall_counters) = &stall_counters {
+                    cfg.app_data(stall_counters.clone());
+                }
+            })
+            .app_data(JsonConfig::default().limit(limits.value))
+            .app_data(PayloadConfig::new(limits.value))
+            .wrap_fn({
+                let watchdog = watchdog.clone();
+                let events = events.clone();
+                move |req, srv| {
+                    // Replicas only serve reads, and nothing else does while disk space is low
+                    let writes = !matches!(*req.method(), Method::GET | Method::HEAD);
+                    let refusal = if writes && read_only {
+                        Some(HttpResponse::MethodNotAllowed().content_type("application/json").body(
+                            json!({ "status": 405, "msg": "This instance is a read-only replica" }).to_string(),
+                        ))
+                    } else if writes && watchdog.as_ref().map_or(false, |w| w.is_low()) {
+                        Some(HttpResponse::InsufficientStorage().content_type("application/json").body(
+                            json!({ "status": 507, "msg": "Free disk space is low, only reads are served" })
+                                .to_string(),
+                        ))
+                    } else {
+                        None
+                    };
+                    let route = format!("{} {}", req.method(), req.path());
+                    let res = match refusal {
+                        Some(res) => Err(req.into_response(res)),
+                        None => Ok(srv.call(req)),
+                    };
+                    let events = events.clone();
+                    async move {
+                        match res {
+                            Ok(fut) => {
+                                let res = fut.await?;
+                                if res.status() == StatusCode::PAYLOAD_TOO_LARGE {
+                                    events.emit("payload_too_large", json!({ "route": route }));
+                                }
+                                Ok(res.map_into_left_body())
+                            }
+                            Err(res) => Ok(res.map_into_right_body()),
+                        }
+                    }
+                }
+            })
+            .wrap_fn({
+                let events = events.clone();
+                move |req, srv| {
+                    // Answers 503 if the handler hasn't produced a response in
+                    // time. Streamed bodies are not limited once they've started,
+                    // and work handed to the blocking pool keeps running.
+                    let route = format!("{} {}", req.method(), req.path());
+                    let fut = srv.call(req);
+                    let events = events.clone();
+                    async move {
+                        if request_timeout == 0 {
+                            return fut.await;
+                        }
+                        let limit = std::time::Duration::from_millis(request_timeout);
+                        match tokio::time::timeout(limit, fut).await {
+                            Ok(res) => res,
+                            Err(_) => {
+                                log::warn!("{route} timed out after {request_timeout}ms");
+                                events.emit(
+                                    "request_timeout",
+                                    json!({ "route": route, "timeout_ms": request_timeout }),
+                                );
+                                let msg = format!("Request did not complete within {request_timeout}ms");
+                                let res = HttpResponse::ServiceUnavailable()
+                                    .content_type("application/json")
+                                    .body(json!({ "status": 503, "msg": msg }).to_string());
+                                Err(InternalError::from_response(msg, res).into())
+                            }
+                        }
+                    }
+                }
+            })
             .wrap(Logger::default())
-            .service(
-                scope("/api")
-                    .service(
-                        resource("/{key}")
-                            .route(get().to(kv_handler::get))
-                            .route(head().to(kv_handler::head))
-                            .route(post().to(kv_handler::post))
-                            .route(delete().to(kv_handler::delete)),
-                    )
-                    .service(resource("").route(post().to(kv_handler::new))),
-            )
-            .service(resource("/benchmark").route(post().to(kv_handler::benchmark)))
+            .configure(|cfg| routes(cfg, &limits))
     })
     .bind(("0.0.0.0", port))?
     .workers(workers)
     .run()
     .await
 }
+
+/// Registers the API routes. Middleware and shared state are set up by the
+/// caller.
+fn routes(cfg: &mut ServiceConfig, limits: &validation::PayloadLimits) {
+    cfg.service(
+        scope("/api")
+            .service(
+                resource("/_alias/{name}")
+                    .app_data(JsonConfig::default().limit(limits.control))
+                    .route(get().to(aliases::get))
+                    .route(put().to(aliases::put))
+                    .route(delete().to(aliases::delete)),
+            )
+            .service(resource("/_backup").route(post().to(backup::create)))
+            .service(resource("/_backup/verify").route(post().to(backup::verify)))
+            .service(resource("/_backup/{id}").route(get().to(backup::download)))
+            .service(resource("/_checkpoint").route(post().to(backup::checkpoint)))
+            .service(resource("/_events").route(get().to(events::stream)))
+            .service(
+                resource("/_export")
+                    .route(get().to(export::export))
+                    .route(post().to(export::export_selected)),
+            )
+            .service(
+                resource("/_fixture")
+                    .route(get().to(fixtures::get))
+                    .route(put().to(fixtures::put)),
+            )
+            .service(resource("/_import").route(post().to(import::import)))
+            .service(resource("/_info").route(get().to(info::info)))
+            .service(
+                resource("/_leases")
+                    .app_data(JsonConfig::default().limit(limits.control))
+                    .route(post().to(leases::grant)),
+            )
+            .service(
+                resource("/_leases/{id}")
+                    .app_data(JsonConfig::default().limit(limits.control))
+                    .route(get().to(leases::get))
+                    .route(put().to(leases::heartbeat))
+                    .route(delete().to(leases::revoke)),
+            )
+            .service(
+                resource("/_locks/{name}")
+                    .app_data(JsonConfig::default().limit(limits.control))
+                    .route(get().to(locks::get))
+                    .route(post().to(locks::acquire))
+                    .route(put().to(locks::renew))
+                    .route(delete().to(locks::release)),
+            )
+            .service(resource("/_lsm").route(get().to(lsm::stats)))
+            .service(
+                resource("/_migrate")
+                    .route(get().to(migrate::status))
+                    .route(post().to(migrate::start)),
+            )
+            .service(resource("/_shadow").route(get().to(shadow::report)))
+            .service(
+                resource("/_seq/{name}")
+                    .route(get().to(sequences::current))
+                    .route(post().to(sequences::next)),
+            )
+            .service(resource("/{key}/_purge").route(post().to(kv_handler::purge)))
+            .service(resource("/{key}/_push").route(post().to(queue::push)))
+            .service(resource("/{key}/_pop").route(post().to(queue::pop)))
+            .service(resource("/{key}/_peek").route(get().to(queue::peek)))
+            .service(resource("/{key}/_append").route(post().to(fields::append)))
+            .service(resource("/{key}/_sadd").route(post().to(fields::sadd)))
+            .service(resource("/{key}/_srem").route(post().to(fields::srem)))
+            .service(resource("/{key}/_smembers").route(get().to(fields::smembers)))
+            .service(
+                resource("/{key}")
+                    .route(get().to(kv_handler::get))
+                    .route(head().to(kv_handler::head))
+                    .route(post().to(kv_handler::post))
+                    .route(delete().to(kv_handler::delete)),
+            )
+            .service(resource("").route(post().to(kv_handler::new))),
+    )
+    .service(resource("/benchmark").route(post().to(kv_handler::benchmark)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv::{KVStore, RocksDB, TempDB};
+    use actix_web::{
+        body::MessageBody,
+        dev::{ServiceFactory, ServiceRequest, ServiceResponse},
+        http::{header, StatusCode},
+        test,
+        web::Data,
+        App, Error,
+    };
+    use serde_json::{json, Value};
+
+    const TOKEN: &str = "secret";
+
+    /// The API as `main` serves it, minus the middleware, over `db`.
+    fn app(
+        db: &RocksDB,
+    ) -> App<
+        impl ServiceFactory<
+            ServiceRequest,
+            Config = (),
+            Response = ServiceResponse<impl MessageBody>,
+            Error = Error,
+            InitError = (),
+        >,
+    > {
+        let limits = validation::PayloadLimits::from_env();
+        App::new()
+            .app_data(Data::new(db.clone()))
+            .app_data(Data::new(TOKEN.to_string()))
+            .app_data(Data::new(validation::Validation::from_env()))
+            .app_data(Data::new(queue::QueueEvents::default()))
+            .app_data(Data::new(events::Events::default()))
+            .configure(move |cfg| routes(cfg, &limits))
+    }
+
+    fn write(uri: &str, value: Value) -> test::TestRequest {
+        test::TestRequest::post().uri(uri).set_json(value)
+    }
+
+    #[actix_web::test]
+    async fn writes_reads_and_deletes_keys() {
+        let tmp = TempDB::open();
+        let app = test::init_service(app(&tmp.db)).await;
+
+        let res =
+            test::call_service(&app, write("/api/user1", json!({ "a": 1 })).to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let req = test::TestRequest::get().uri("/api/user1").to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body, json!({ "a": 1 }));
+        let req = test::TestRequest::get()
+            .uri("/api/user1?pointer=/a")
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body, json!(1));
+
+        let req = test::TestRequest::delete().uri("/api/user1").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        let req = test::TestRequest::get().uri("/api/user1").to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[actix_web::test]
+    async fn keys_written_under_a_lease_go_with_it() {
+        let tmp = TempDB::open();
+        let app = test::init_service(app(&tmp.db)).await;
+
+        let req = write("/api/_leases", json!({ "ttl": 60000 })).to_request();
+        let lease: Value = test::call_and_read_body_json(&app, req).await;
+        let id = lease["id"].as_str().unwrap();
+        let req = write(&format!("/api/worker1?lease={id}"), json!({ "up": true })).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        let req = write("/api/worker2?lease=missing", json!({ "up": true })).to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::NOT_FOUND
+        );
+
+        let req = test::TestRequest::delete()
+            .uri(&format!("/api/_leases/{id}"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        assert!(tmp.db.find("worker1").is_none());
+    }
+
+    #[actix_web::test]
+    async fn rewritten_keys_leave_their_lease() {
+        let tmp = TempDB::open();
+        let app = test::init_service(app(&tmp.db)).await;
+        let mut leases = Vec::new();
+        for _ in 0..2 {
+            let req = write("/api/_leases", json!({ "ttl": 60000 })).to_request();
+            let lease: Value = test::call_and_read_body_json(&app, req).await;
+            leases.push(lease["id"].as_str().unwrap().to_string());
+        }
+        let revoke = |id: &str| {
+            test::TestRequest::delete()
+                .uri(&format!("/api/_leases/{id}"))
+                .to_request()
+        };
+
+        // Moved to the second lease, then off leases altogether
+        for uri in [
+            format!("/api/worker1?lease={}", leases[0]),
+            format!("/api/worker1?lease={}", leases[1]),
+            format!("/api/worker2?lease={}", leases[1]),
+        ] {
+            let req = write(&uri, json!({ "up": true })).to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        }
+        let req = write("/api/worker2", json!({ "up": false })).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        test::call_service(&app, revoke(&leases[0])).await;
+        assert!(tmp.db.find("worker1").is_some());
+        test::call_service(&app, revoke(&leases[1])).await;
+        assert!(tmp.db.find("worker1").is_none());
+        assert_eq!(tmp.db.find("worker2").as_deref(), Some(r#"{"up":false}"#));
+    }
+
+    #[actix_web::test]
+    async fn locks_fence_their_holders() {
+        let tmp = TempDB::open();
+        let app = test::init_service(app(&tmp.db)).await;
+
+        let acquire = |owner: &str| {
+            write("/api/_locks/jobs", json!({ "owner": owner, "ttl": 60000 })).to_request()
+        };
+        let lock: Value = test::call_and_read_body_json(&app, acquire("a")).await;
+        assert_eq!(lock["token"], 1);
+        let res = test::call_service(&app, acquire("b")).await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+
+        let req = test::TestRequest::delete()
+            .uri("/api/_locks/jobs")
+            .set_json(json!({ "owner": "a", "token": 1 }))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        let lock: Value = test::call_and_read_body_json(&app, acquire("b")).await;
+        assert_eq!(lock["token"], 2);
+    }
+
+    #[actix_web::test]
+    async fn queues_and_sets_change_values_in_place() {
+        let tmp = TempDB::open();
+        let app = test::init_service(app(&tmp.db)).await;
+
+        for job in [1, 2] {
+            let req = write("/api/jobs/_push", json!({ "job": job })).to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        }
+        let req = test::TestRequest::post().uri("/api/jobs/_pop").to_request();
+        let job: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(job, json!({ "job": 1 }));
+
+        let req = write("/api/user1/_sadd?field=/tags", json!(["red", "blue"])).to_request();
+        let res: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(res, json!({ "added": 2, "size": 2 }));
+        let req = write("/api/user1/_srem?field=/tags", json!(["red"])).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        let req = test::TestRequest::get()
+            .uri("/api/user1/_smembers?field=/tags")
+            .to_request();
+        let members: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(members, json!(["blue"]));
+    }
+
+    #[actix_web::test]
+    async fn sets_refuse_internal_state() {
+        let tmp = TempDB::open();
+        let app = test::init_service(app(&tmp.db)).await;
+        let state = r#"{"skipped_keys":["a"]}"#;
+        tmp.db.save("_migrate", state);
+
+        let req = write("/api/_migrate/_srem?field=/skipped_keys", json!(["a"])).to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+        let req = test::TestRequest::get()
+            .uri("/api/_migrate/_smembers?field=/skipped_keys")
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(tmp.db.find("_migrate").as_deref(), Some(state));
+    }
+
+    #[actix_web::test]
+    async fn queues_refuse_internal_state() {
+        let tmp = TempDB::open();
+        let app = test::init_service(app(&tmp.db)).await;
+        let state = r#"{"skipped_keys":["a"]}"#;
+        tmp.db.save("_migrate", state);
+
+        for req in [
+            test::TestRequest::post().uri("/api/_migrate/_pop"),
+            test::TestRequest::get().uri("/api/_migrate/_peek"),
+        ] {
+            assert_eq!(
+                test::call_service(&app, req.to_request()).await.status(),
+                StatusCode::BAD_REQUEST
+            );
+        }
+        assert_eq!(tmp.db.find("_migrate").as_deref(), Some(state));
+    }
+
+    #[actix_web::test]
+    async fn exports_load_into_another_instance() {
+        let (from, to) = (TempDB::open(), TempDB::open());
+        let source = test::init_service(app(&from.db)).await;
+        let target = test::init_service(app(&to.db)).await;
+        for (key, value) in [("a", json!(1)), ("b", json!({ "c": [true] }))] {
+            let req = write(&format!("/api/{key}"), value).to_request();
+            assert_eq!(
+                test::call_service(&source, req).await.status(),
+                StatusCode::OK
+            );
+        }
+
+        let req = test::TestRequest::get()
+            .uri("/api/_export")
+            .insert_header((header::AUTHORIZATION, TOKEN))
+            .to_request();
+        let export = test::call_and_read_body(&source, req).await;
+        let req = test::TestRequest::post()
+            .uri("/api/_import")
+            .insert_header((header::AUTHORIZATION, TOKEN))
+            .set_payload(export)
+            .to_request();
+        let res: Value = test::call_and_read_body_json(&target, req).await;
+        assert_eq!(res, json!({ "imported": 2, "skipped": 0 }));
+        assert_eq!(to.db.find("b").as_deref(), Some(r#"{"c":[true]}"#));
+    }
+
+    #[actix_web::test]
+    async fn admin_routes_require_the_token() {
+        let tmp = TempDB::open();
+        let app = test::init_service(app(&tmp.db)).await;
+        for uri in ["/api/_export", "/api/_lsm", "/api/_shadow"] {
+            let req = test::TestRequest::get().uri(uri).to_request();
+            assert_eq!(
+                test::call_service(&app, req).await.status(),
+                StatusCode::UNAUTHORIZED,
+                "{uri}"
+            );
+        }
+    }
+}
@@ -0,0 +1,204 @@
+// In-memory stand-in for the parts of the `rocksdb` API the store uses, so
+// the server can run without disk or the native library (`--features memory`).
+// Nothing is persisted; checkpoints and secondary instances are unsupported.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    ops::Bound,
+    path::Path,
+    sync::{PoisonError, RwLock},
+};
+
+#[derive(Debug)]
+pub struct Error(String);
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl<T> From<PoisonError<T>> for Error {
+    fn from(_: PoisonError<T>) -> Self {
+        Error("in-memory store lock poisoned".to_string())
+    }
+}
+
+fn unsupported(what: &str) -> Error {
+    Error(format!("{what} is not supported by the in-memory backend"))
+}
+
+pub enum Direction {
+    Forward,
+}
+
+pub enum IteratorMode<'a> {
+    Start,
+    From(&'a [u8], Direction),
+}
+
+#[derive(Default)]
+pub struct Options {}
+
+impl Options {
+    pub fn create_if_missing(&mut self, _: bool) {}
+    pub fn set_max_open_files(&mut self, _: i32) {}
+    pub fn set_write_buffer_manager(&mut self, _: &WriteBufferManager) {}
+    pub fn set_block_based_table_factory(&mut self, _: &BlockBasedOptions) {}
+}
+
+#[derive(Clone)]
+pub struct Cache;
+
+impl Cache {
+    pub fn new_lru_cache(_: usize) -> Cache {
+        Cache
+    }
+}
+
+pub struct WriteBufferManager;
+
+impl WriteBufferManager {
+    pub fn new_write_buffer_manager_with_cache(_: usize, _: bool, _: Cache) -> Self {
+        WriteBufferManager
+    }
+}
+
+#[derive(Default)]
+pub struct BlockBasedOptions {}
+
+impl BlockBasedOptions {
+    pub fn set_block_cache(&mut self, _: &Cache) {}
+}
+
+pub enum BottommostLevelCompaction {
+    Force,
+}
+
+#[derive(Default)]
+pub struct CompactOptions {}
+
+impl CompactOptions {
+    pub fn set_bottommost_level_compaction(&mut self, _: BottommostLevelCompaction) {}
+}
+
+#[derive(Default)]
+pub struct WriteBatch {
+    /// Writes in order, `None` deleting the key
+    ops: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+}
+
+impl WriteBatch {
+    pub fn put<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V) {
+        self.ops
+            .push((key.as_ref().to_vec(), Some(value.as_ref().to_vec())));
+    }
+
+    pub fn delete<K: AsRef<[u8]>>(&mut self, key: K) {
+        self.ops.push((key.as_ref().to_vec(), None));
+    }
+}
+
+#[derive(Default)]
+pub struct DB {
+    map: RwLock<BTreeMap<Vec<u8>, Vec<u8>>>,
+}
+
+type KVBytes = (Box<[u8]>, Box<[u8]>);
+
+impl DB {
+    pub fn open_default<P: AsRef<Path>>(_: P) -> Result<Self, Error> {
+        Ok(DB::default())
+    }
+
+    pub fn open<P: AsRef<Path>>(_: &Options, _: P) -> Result<Self, Error> {
+        Ok(DB::default())
+    }
+
+    pub fn open_as_secondary<P: AsRef<Path>>(_: &Options, _: P, _: P) -> Result<Self, Error> {
+        Err(unsupported("opening a secondary instance"))
+    }
+
+    pub fn try_catch_up_with_primary(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.map.read()?.get(key.as_ref()).cloned())
+    }
+
+    pub fn put<K: AsRef<[u8]>, V: AsRef<[u8]>>(&self, key: K, value: V) -> Result<(), Error> {
+        self.map
+            .write()?
+            .insert(key.as_ref().to_vec(), value.as_ref().to_vec());
+        Ok(())
+    }
+
+    pub fn delete<K: AsRef<[u8]>>(&self, key: K) -> Result<(), Error> {
+        self.map.write()?.remove(key.as_ref());
+        Ok(())
+    }
+
+    pub fn write(&self, batch: WriteBatch) -> Result<(), Error> {
+        let mut map = self.map.write()?;
+        for (key, value) in batch.ops {
+            match value {
+                Some(value) => map.insert(key, value),
+                None => map.remove(&key),
+            };
+        }
+        Ok(())
+    }
+
+    pub fn compact_range_opt<S: AsRef<[u8]>, E: AsRef<[u8]>>(
+        &self,
+        _: Option<S>,
+        _: Option<E>,
+        _: &CompactOptions,
+    ) {
+    }
+
+    pub fn property_value(&self, _: &str) -> Result<Option<String>, Error> {
+        Ok(None)
+    }
+
+    pub fn property_int_value(&self, _: &str) -> Result<Option<u64>, Error> {
+        Ok(None)
+    }
+
+    /// Iterates over a snapshot of the entries taken when called.
+    pub fn iterator(&self, mode: IteratorMode) -> std::vec::IntoIter<Result<KVBytes, Error>> {
+        let map = match self.map.read() {
+            Ok(map) => map,
+            Err(e) => return vec![Err(e.into())].into_iter(),
+        };
+        let start = match mode {
+            IteratorMode::Start => Bound::Unbounded,
+            IteratorMode::From(key, Direction::Forward) => Bound::Included(key.to_vec()),
+        };
+        map.range((start, Bound::Unbounded))
+            .map(|(k, v)| Ok((k.clone().into_boxed_slice(), v.clone().into_boxed_slice())))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+}
+
+pub mod checkpoint {
+    use super::{unsupported, Error, DB};
+    use std::path::Path;
+
+    pub struct Checkpoint;
+
+    impl Checkpoint {
+        pub fn new(_: &DB) -> Result<Self, Error> {
+            Err(unsupported("creating a checkpoint"))
+        }
+
+        pub fn create_checkpoint<P: AsRef<Path>>(&self, _: P) -> Result<(), Error> {
+            Err(unsupported("creating a checkpoint"))
+        }
+    }
+}
@@ -0,0 +1,203 @@
+use crate::auth;
+use crate::kv::{KVStore, RocksDB};
+use crate::validation::Validation;
+
+use actix_web::{
+    http::{header, StatusCode},
+    web::{Data, Json},
+    HttpRequest, HttpResponse,
+};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+const STATE_KEY: &str = "_migrate";
+/// Keys copied between progress checkpoints.
+const CHECKPOINT_EVERY: u64 = 100;
+/// Rejected keys listed in the progress report, the rest are only counted.
+const SKIPPED_KEPT: usize = 100;
+
+/// Progress of the last migration, kept under `_migrate` so an interrupted
+/// one can pick up after the last checkpointed key.
+#[derive(Serialize, Deserialize, Default)]
+struct State {
+    target: String,
+    last_key: Option<String>,
+    copied: u64,
+    /// Keys the target rejected, e.g. because its key rules are stricter
+    #[serde(default)]
+    skipped: u64,
+    #[serde(default)]
+    skipped_keys: Vec<String>,
+    done: bool,
+    error: Option<String>,
+}
+
+/// Makes sure only one migration runs at a time.
+#[derive(Default)]
+pub struct Migrator {
+    running: AtomicBool,
+}
+
+#[derive(Deserialize)]
+pub struct Start {
+    /// Base URL of the smol-kv instance to copy to
+    target: String,
+    /// Start over instead of resuming a previous migration to the same target
+    #[serde(default)]
+    restart: bool,
+}
+
+fn load_state(db: &RocksDB) -> Option<State> {
+    db.find(STATE_KEY)
+        .and_then(|v| serde_json::from_str(&v).ok())
+}
+
+fn save_state(db: &RocksDB, state: &State) {
+    if !db.save(STATE_KEY, &serde_json::to_string(state).unwrap()) {
+        log::error!("Error saving migration progress");
+    }
+}
+
+fn state_json(state: &State, running: bool) -> String {
+    json!({
+        "target": state.target,
+        "running": running,
+        "done": state.done,
+        "copied": state.copied,
+        "skipped": state.skipped,
+        "skipped_keys": state.skipped_keys,
+        "last_key": state.last_key,
+        "error": state.error,
+    })
+    .to_string()
+}
+
+/// Copies every user key after `state.last_key` to the target in key order,
+/// checkpointing progress as it goes. Keys the target rejects with a client
+/// error are recorded and skipped, anything else stops the copy.
+async fn run(db: &RocksDB, validation: &Validation, state: &mut State) -> Result<(), String> {
+    let client = awc::Client::default();
+    let start = state.last_key.clone().unwrap_or_default();
+    for item in db.iter_from(&start) {
+        let (k, v) = item.map_err(|e| e.to_string())?;
+        let key = String::from_utf8_lossy(&k).into_owned();
+        if state.last_key.as_deref() == Some(key.as_str()) || validation.is_reserved(&key) {
+            continue;
+        }
+
+        let res = client
+            .post(format!(
+                "{}/api/{}",
+                state.target,
+                utf8_percent_encode(&key, NON_ALPHANUMERIC)
+            ))
+            .insert_header((header::CONTENT_TYPE, "application/json"))
+            .send_body(v.into_vec())
+            .await
+            .map_err(|e| format!("{key}: {e}"))?;
+        match res.status() {
+            status if status.is_success() => state.copied += 1,
+            // Retrying won't help with these, so don't let one key hold up the rest
+            status if status.is_client_error() && status != StatusCode::TOO_MANY_REQUESTS => {
+                log::warn!(
+                    "Migration skipped {}: target responded with {}",
+                    key,
+                    status
+                );
+                state.skipped += 1;
+                if state.skipped_keys.len() < SKIPPED_KEPT {
+                    state.skipped_keys.push(format!("{key}: {status}"));
+                }
+            }
+            status => return Err(format!("{key}: target responded with {status}")),
+        }
+
+        state.last_key = Some(key);
+        if (state.copied + state.skipped) % CHECKPOINT_EVERY == 0 {
+            save_state(db, state);
+        }
+    }
+    Ok(())
+}
+
+/// Starts copying all keys to another instance in the background, resuming
+/// the previous migration to the same target unless asked to restart.
+pub async fn start(
+    db: Data<RocksDB>,
+    validation: Data<Validation>,
+    migrator: Data<Migrator>,
+    token: Data<String>,
+    body: Json<Start>,
+    req: HttpRequest,
+) -> HttpResponse {
+    if !auth::is_admin(&req, &token) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    if migrator.running.swap(true, Ordering::SeqCst) {
+        return HttpResponse::Conflict()
+            .content_type("application/json")
+            .body(json!({ "status": 409, "msg": "A migration is already running" }).to_string());
+    }
+
+    let Start { target, restart } = body.into_inner();
+    let target = target.trim_end_matches('/').to_string();
+    let mut state = match load_state(&db) {
+        Some(state) if state.target == target && !state.done && !restart => state,
+        _ => State {
+            target,
+            ..State::default()
+        },
+    };
+    state.error = None;
+    save_state(&db, &state);
+    let body = state_json(&state, true);
+
+    let (db, validation, migrator) = (db.clone(), validation.clone(), migrator.clone());
+    // The HTTP client isn't Send, so the copy gets a runtime of its own
+    std::thread::spawn(move || {
+        actix_web::rt::System::new().block_on(async move {
+            match run(&db, &validation, &mut state).await {
+                Ok(()) => {
+                    state.done = true;
+                    log::info!(
+                        "Migration to {} copied {} keys, skipped {}",
+                        state.target,
+                        state.copied,
+                        state.skipped
+                    );
+                }
+                Err(e) => {
+                    log::error!("Migration to {} stopped: {}", state.target, e);
+                    state.error = Some(e);
+                }
+            }
+            save_state(&db, &state);
+            migrator.running.store(false, Ordering::SeqCst);
+        })
+    });
+
+    HttpResponse::Accepted()
+        .content_type("application/json")
+        .body(body)
+}
+
+pub async fn status(
+    db: Data<RocksDB>,
+    migrator: Data<Migrator>,
+    token: Data<String>,
+    req: HttpRequest,
+) -> HttpResponse {
+    if !auth::is_admin(&req, &token) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    match load_state(&db) {
+        Some(state) => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(state_json(&state, migrator.running.load(Ordering::SeqCst))),
+        None => HttpResponse::NotFound()
+            .content_type("application/json")
+            .finish(),
+    }
+}
@@ -0,0 +1,285 @@
+use crate::encoding;
+use crate::kv::{self, RocksDB};
+use crate::validation::Validation;
+
+use actix_web::{
+    web::{Data, Path, Query},
+    HttpRequest, HttpResponse,
+};
+use bytes::Bytes;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{pin::pin, time::Duration};
+use tokio::{sync::Notify, time::Instant};
+
+/// Longest a blocking pop may wait, in seconds.
+const MAX_WAIT: u64 = 60;
+
+/// Wakes blocked pops whenever something is pushed to any queue.
+#[derive(Default)]
+pub struct QueueEvents {
+    pushed: Notify,
+}
+
+#[derive(Deserialize)]
+pub struct PopParams {
+    /// Seconds to wait for an item when the queue is empty
+    #[serde(default)]
+    wait: u64,
+}
+
+enum Change {
+    Done(Option<Value>),
+    NotAList,
+    TooLarge,
+}
+
+fn not_a_list() -> HttpResponse {
+    HttpResponse::Conflict()
+        .content_type("application/json")
+        .body(
+            json!({ "status": 409, "msg": "Value stored at this key is not an array" }).to_string(),
+        )
+}
+
+fn internal_error(key: &str, e: kv::Error) -> HttpResponse {
+    log::error!("Error updating queue {}: {}", key, e);
+    HttpResponse::InternalServerError()
+        .content_type("application/json")
+        .finish()
+}
+
+/// Applies `f` to the array stored at `key` (empty if missing) atomically.
+/// With a `limit`, changes that grow the stored array past that many bytes
+/// are dropped.
+fn modify(
+    db: &RocksDB,
+    key: &str,
+    limit: Option<usize>,
+    f: impl FnOnce(&mut Vec<Value>) -> Option<Value>,
+) -> Result<Change, kv::Error> {
+    db.update(key, |current| {
+        let mut items = match current.as_deref().map(serde_json::from_str::<Value>) {
+            None => Vec::new(),
+            Some(Ok(Value::Array(items))) => items,
+            Some(_) => return (current, Change::NotAList),
+        };
+        let result = f(&mut items);
+        // Reading an empty queue shouldn't create the key
+        let next = match (&current, items.is_empty()) {
+            (None, true) => None,
+            _ => Some(Value::Array(items).to_string()),
+        };
+        let size = |v: &Option<String>| v.as_ref().map_or(0, String::len);
+        if limit.map_or(false, |limit| {
+            size(&next) > limit && size(&next) > size(&current)
+        }) {
+            return (current, Change::TooLarge);
+        }
+        (next, Change::Done(result))
+    })
+}
+
+fn respond(
+    req: &HttpRequest,
+    validation: &Validation,
+    key: &str,
+    change: Result<Change, kv::Error>,
+) -> HttpResponse {
+    match change {
+        Ok(Change::Done(Some(item))) => encoding::respond(req, HttpResponse::Ok(), &item),
+        Ok(Change::Done(None)) => HttpResponse::NoContent().finish(),
+        Ok(Change::NotAList) => not_a_list(),
+        Ok(Change::TooLarge) => validation.too_large(),
+        Err(e) => internal_error(key, e),
+    }
+}
+
+/// Appends the request body to the tail of the queue.
+pub async fn push(
+    key: Path<String>,
+    db: Data<RocksDB>,
+    validation: Data<Validation>,
+    events: Data<QueueEvents>,
+    body: Bytes,
+    req: HttpRequest,
+) -> HttpResponse {
+    let key = key.into_inner();
+    if let Err(res) = validation
+        .check_key(&key)
+        .and_then(|_| validation.check_value(&body))
+    {
+        return res;
+    }
+    let item = match encoding::decode(&req, &body) {
+        Ok(item) => item,
+        Err(res) => return res,
+    };
+
+    let change = modify(&db, &key, Some(validation.max_value_size()), |items| {
+        items.push(item);
+        Some(json!({ "length": items.len() }))
+    });
+    if let Ok(Change::Done(_)) = change {
+        events.pushed.notify_waiters();
+    }
+    respond(&req, &validation, &key, change)
+}
+
+/// Removes and returns the head of the queue. With `?wait=N`, an empty queue
+/// holds the request for up to N seconds until something is pushed; otherwise
+/// (or on timeout) it responds with 204.
+pub async fn pop(
+    key: Path<String>,
+    db: Data<RocksDB>,
+    validation: Data<Validation>,
+    events: Data<QueueEvents>,
+    params: Query<PopParams>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let key = key.into_inner();
+    if let Err(res) = validation.check_key(&key) {
+        return res;
+    }
+    let deadline = Instant::now() + Duration::from_secs(params.wait.min(MAX_WAIT));
+
+    loop {
+        // Register before checking so a push in between isn't missed
+        let mut pushed = pin!(events.pushed.notified());
+        pushed.as_mut().enable();
+
+        let change = modify(&db, &key, None, |items| {
+            (!items.is_empty()).then(|| items.remove(0))
+        });
+        match change {
+            Ok(Change::Done(None)) => {}
+            other => return respond(&req, &validation, &key, other),
+        }
+        if tokio::time::timeout_at(deadline, pushed).await.is_err() {
+            return HttpResponse::NoContent().finish();
+        }
+    }
+}
+
+/// Returns the head of the queue without removing it.
+pub async fn peek(
+    key: Path<String>,
+    db: Data<RocksDB>,
+    validation: Data<Validation>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let key = key.into_inner();
+    if let Err(res) = validation.check_key(&key) {
+        return res;
+    }
+    let change = modify(&db, &key, None, |items| items.first().cloned());
+    respond(&req, &validation, &key, change)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv::{KVStore, TempDB};
+    use actix_web::{body::to_bytes, http::StatusCode, test::TestRequest};
+
+    async fn json_body(res: HttpResponse) -> Value {
+        serde_json::from_slice(&to_bytes(res.into_body()).await.unwrap()).unwrap()
+    }
+
+    struct Queue {
+        _tmp: TempDB,
+        db: Data<RocksDB>,
+        validation: Data<Validation>,
+        events: Data<QueueEvents>,
+    }
+
+    impl Queue {
+        fn new() -> Self {
+            let tmp = TempDB::open();
+            Queue {
+                db: Data::new(tmp.db.clone()),
+                _tmp: tmp,
+                validation: Data::new(Validation::from_env()),
+                events: Data::new(QueueEvents::default()),
+            }
+        }
+
+        async fn push(&self, item: Value) -> HttpResponse {
+            let req = TestRequest::default().to_http_request();
+            let body = Bytes::from(item.to_string());
+            let (db, validation, events) = (
+                self.db.clone(),
+                self.validation.clone(),
+                self.events.clone(),
+            );
+            push(
+                Path::from("jobs".to_string()),
+                db,
+                validation,
+                events,
+                body,
+                req,
+            )
+            .await
+        }
+
+        async fn pop(&self, wait: u64) -> HttpResponse {
+            let req = TestRequest::default().to_http_request();
+            let params = Query(PopParams { wait });
+            let (db, validation, events) = (
+                self.db.clone(),
+                self.validation.clone(),
+                self.events.clone(),
+            );
+            pop(
+                Path::from("jobs".to_string()),
+                db,
+                validation,
+                events,
+                params,
+                req,
+            )
+            .await
+        }
+
+        async fn peek(&self) -> HttpResponse {
+            let req = TestRequest::default().to_http_request();
+            let (db, validation) = (self.db.clone(), self.validation.clone());
+            peek(Path::from("jobs".to_string()), db, validation, req).await
+        }
+    }
+
+    #[actix_web::test]
+    async fn pops_in_push_order() {
+        let queue = Queue::new();
+        for n in 1..=3 {
+            let res = queue.push(json!({ "n": n })).await;
+            assert_eq!(json_body(res).await, json!({ "length": n }));
+        }
+
+        assert_eq!(json_body(queue.peek().await).await, json!({ "n": 1 }));
+        for n in 1..=3 {
+            assert_eq!(json_body(queue.pop(0).await).await, json!({ "n": n }));
+        }
+        assert_eq!(queue.pop(0).await.status(), StatusCode::NO_CONTENT);
+    }
+
+    #[actix_web::test]
+    async fn blocking_pop_gets_a_later_push() {
+        let queue = std::rc::Rc::new(Queue::new());
+        let pusher = queue.clone();
+        actix_web::rt::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            pusher.push(json!("late")).await;
+        });
+        assert_eq!(json_body(queue.pop(5).await).await, json!("late"));
+    }
+
+    #[actix_web::test]
+    async fn refuses_other_values() {
+        let queue = Queue::new();
+        queue.db.save("jobs", r#"{"not":"a list"}"#);
+        assert_eq!(queue.push(json!(1)).await.status(), StatusCode::CONFLICT);
+        assert_eq!(queue.pop(0).await.status(), StatusCode::CONFLICT);
+    }
+}
@@ -0,0 +1,86 @@
+use crate::kv::{KVStore, RocksDB};
+use crate::validation::Validation;
+
+use actix_web::{
+    web::{Data, Path, Query},
+    HttpResponse,
+};
+use serde::Deserialize;
+use serde_json::json;
+
+/// Most values a single call may allocate.
+const MAX_COUNT: u64 = 10_000;
+
+#[derive(Deserialize)]
+pub struct AllocParams {
+    /// How many consecutive values to allocate at once
+    #[serde(default = "one")]
+    count: u64,
+}
+
+fn one() -> u64 {
+    1
+}
+
+fn seq_key(name: &str) -> String {
+    format!("_seq/{name}")
+}
+
+fn bad_request(msg: String) -> HttpResponse {
+    HttpResponse::BadRequest()
+        .content_type("application/json")
+        .body(json!({ "status": 400, "msg": msg }).to_string())
+}
+
+/// Hands out the next `count` values of the sequence, starting at 1. Values
+/// are never reused, even across restarts.
+pub async fn next(
+    name: Path<String>,
+    db: Data<RocksDB>,
+    validation: Data<Validation>,
+    params: Query<AllocParams>,
+) -> HttpResponse {
+    let name = name.into_inner();
+    if let Err(res) = validation.check_key(&name) {
+        return res;
+    }
+    if params.count == 0 || params.count > MAX_COUNT {
+        return bad_request(format!("count must be between 1 and {MAX_COUNT}"));
+    }
+
+    let result = db.update(&seq_key(&name), |current| {
+        let last = current
+            .as_deref()
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or(0);
+        match last.checked_add(params.count) {
+            Some(next) => (Some(next.to_string()), Some((last + 1, next))),
+            None => (current, None),
+        }
+    });
+    match result {
+        Ok(Some((first, last))) => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(json!({ "name": name, "first": first, "last": last }).to_string()),
+        Ok(None) => bad_request("Sequence is exhausted".to_string()),
+        Err(e) => {
+            log::error!("Error updating sequence {}: {}", name, e);
+            HttpResponse::InternalServerError()
+                .content_type("application/json")
+                .finish()
+        }
+    }
+}
+
+/// Returns the last value handed out, without allocating.
+pub async fn current(name: Path<String>, db: Data<RocksDB>) -> HttpResponse {
+    let name = name.into_inner();
+    match db.find(&seq_key(&name)) {
+        Some(last) => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(json!({ "name": name, "last": last.parse::<u64>().unwrap_or(0) }).to_string()),
+        None => HttpResponse::NotFound()
+            .content_type("application/json")
+            .finish(),
+    }
+}
@@ -0,0 +1,228 @@
+use crate::auth;
+use crate::validation::Validation;
+
+use actix_web::{
+    http::header,
+    web::{Data, Query},
+    HttpRequest, HttpResponse,
+};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::Deserialize;
+use serde_json::json;
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+enum Op {
+    Put(String, String),
+    Delete(String),
+}
+
+struct Queued {
+    op: Op,
+    at: Instant,
+}
+
+#[derive(Default)]
+struct Stats {
+    forwarded: u64,
+    failed: u64,
+    /// Time between the local write and its copy landing, for the latest one
+    lag_ms: u64,
+    last_error: Option<String>,
+}
+
+/// Mirrors writes to a second smol-kv instance in the background, in the
+/// order they were made here, to validate it before a migration cutover.
+/// Writes that find the queue full are dropped and counted.
+pub struct Shadow {
+    url: String,
+    prefixes: Vec<String>,
+    validation: Validation,
+    tx: mpsc::Sender<Queued>,
+    pending: AtomicU64,
+    dropped: AtomicU64,
+    stats: Mutex<Stats>,
+}
+
+#[derive(Deserialize)]
+pub struct ReportParams {
+    /// Clear the error counters and last error after reading them
+    #[serde(default)]
+    reset: bool,
+}
+
+impl Shadow {
+    /// Starts mirroring if `SHADOW_URL` is set. `SHADOW_PREFIXES` limits it to
+    /// keys starting with any of the comma separated prefixes, and
+    /// `SHADOW_QUEUE_SIZE` caps how many writes may wait to be copied.
+    pub fn from_env(validation: &Validation) -> Option<Arc<Self>> {
+        let url = std::env::var("SHADOW_URL").ok()?;
+        let prefixes = std::env::var("SHADOW_PREFIXES")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|p| !p.is_empty())
+            .map(String::from)
+            .collect();
+        let queue_size = std::env::var("SHADOW_QUEUE_SIZE")
+            .unwrap_or("10000".to_string())
+            .parse::<usize>()
+            .unwrap();
+        Some(Self::spawn(
+            url.trim_end_matches('/').to_string(),
+            prefixes,
+            validation.clone(),
+            queue_size,
+        ))
+    }
+
+    fn spawn(
+        url: String,
+        prefixes: Vec<String>,
+        validation: Validation,
+        queue_size: usize,
+    ) -> Arc<Self> {
+        let (tx, mut rx) = mpsc::channel::<Queued>(queue_size);
+        let shadow = Arc::new(Shadow {
+            url,
+            prefixes,
+            validation,
+            tx,
+            pending: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            stats: Mutex::new(Stats::default()),
+        });
+
+        let worker = shadow.clone();
+        // The HTTP client isn't Send, so it gets a runtime of its own
+        std::thread::spawn(move || {
+            actix_web::rt::System::new().block_on(async move {
+                let client = awc::Client::default();
+                while let Some(queued) = rx.recv().await {
+                    let result = worker.forward(&client, queued.op).await;
+                    worker.pending.fetch_sub(1, Ordering::Relaxed);
+                    let mut stats = worker.stats.lock().unwrap_or_else(|e| e.into_inner());
+                    match result {
+                        Ok(()) => {
+                            stats.forwarded += 1;
+                            stats.lag_ms = queued.at.elapsed().as_millis() as u64;
+                        }
+                        Err(e) => {
+                            log::warn!("Shadow write failed: {}", e);
+                            stats.failed += 1;
+                            stats.last_error = Some(e);
+                        }
+                    }
+                }
+            })
+        });
+        shadow
+    }
+
+    fn key_url(&self, key: &str) -> String {
+        format!(
+            "{}/api/{}",
+            self.url,
+            utf8_percent_encode(key, NON_ALPHANUMERIC)
+        )
+    }
+
+    async fn forward(&self, client: &awc::Client, op: Op) -> Result<(), String> {
+        let (key, res) = match op {
+            Op::Put(key, value) => {
+                let res = client
+                    .post(self.key_url(&key))
+                    .insert_header((header::CONTENT_TYPE, "application/json"))
+                    .send_body(value)
+                    .await;
+                (key, res)
+            }
+            Op::Delete(key) => {
+                let res = client.delete(self.key_url(&key)).send().await;
+                (key, res)
+            }
+        };
+        match res {
+            Ok(res) if res.status().is_success() => Ok(()),
+            Ok(res) => Err(format!("{key}: target responded with {}", res.status())),
+            Err(e) => Err(format!("{key}: {e}")),
+        }
+    }
+
+    fn mirrors(&self, key: &str) -> bool {
+        !self.validation.is_reserved(key)
+            && (self.prefixes.is_empty() || self.prefixes.iter().any(|p| key.starts_with(p)))
+    }
+
+    /// Queues a copy of a local write, `None` meaning the key was deleted.
+    /// Callers make sure writes to the same key are recorded in the order
+    /// they were applied.
+    pub fn record(&self, key: &str, value: Option<&str>) {
+        if !self.mirrors(key) {
+            return;
+        }
+        let op = match value {
+            Some(value) => Op::Put(key.to_string(), value.to_string()),
+            None => Op::Delete(key.to_string()),
+        };
+        self.pending.fetch_add(1, Ordering::Relaxed);
+        let queued = Queued {
+            op,
+            at: Instant::now(),
+        };
+        match self.tx.try_send(queued) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                self.pending.fetch_sub(1, Ordering::Relaxed);
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                log::warn!("Shadow queue is full, dropping write to {}", key);
+            }
+            Err(TrySendError::Closed(_)) => {
+                self.pending.fetch_sub(1, Ordering::Relaxed);
+                log::error!("Shadow writer stopped, dropping write to {}", key);
+            }
+        }
+    }
+}
+
+/// Reports how far behind the shadow instance is and what failed.
+pub async fn report(
+    shadow: Option<Data<Shadow>>,
+    token: Data<String>,
+    params: Query<ReportParams>,
+    req: HttpRequest,
+) -> HttpResponse {
+    if !auth::is_admin(&req, &token) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let Some(shadow) = shadow else {
+        return HttpResponse::NotFound()
+            .content_type("application/json")
+            .body(json!({ "status": 404, "msg": "Shadow writes are not enabled" }).to_string());
+    };
+
+    let mut stats = shadow.stats.lock().unwrap_or_else(|e| e.into_inner());
+    let body = json!({
+        "url": shadow.url,
+        "pending": shadow.pending.load(Ordering::Relaxed),
+        "forwarded": stats.forwarded,
+        "failed": stats.failed,
+        "dropped": shadow.dropped.load(Ordering::Relaxed),
+        "lag_ms": stats.lag_ms,
+        "last_error": stats.last_error,
+    });
+    if params.reset {
+        shadow.dropped.store(0, Ordering::Relaxed);
+        stats.failed = 0;
+        stats.last_error = None;
+    }
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(body.to_string())
+}
@@ -0,0 +1,98 @@
+use crate::events::Events;
+use crate::kv::RocksDB;
+
+use actix_web::web::Data;
+use serde_json::json;
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    sync::Arc,
+    time::Duration,
+};
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Normal,
+    /// RocksDB is slowing writes down to let compaction catch up
+    Delayed,
+    /// Writes are blocked until compaction catches up
+    Stopped,
+}
+
+impl State {
+    fn name(self) -> &'static str {
+        match self {
+            State::Normal => "normal",
+            State::Delayed => "delayed",
+            State::Stopped => "stopped",
+        }
+    }
+}
+
+/// Counts how often writes started being delayed or stopped.
+#[derive(Default)]
+pub struct StallCounters {
+    pub delayed: AtomicU64,
+    pub stopped: AtomicU64,
+}
+
+fn current(db: &RocksDB) -> State {
+    if db.property_int("rocksdb.is-write-stopped").unwrap_or(0) > 0 {
+        State::Stopped
+    } else if db
+        .property_int("rocksdb.actual-delayed-write-rate")
+        .unwrap_or(0)
+        > 0
+    {
+        State::Delayed
+    } else {
+        State::Normal
+    }
+}
+
+/// Polls RocksDB every `interval` and emits a `write_stall` event whenever
+/// writes start or stop being delayed or blocked.
+pub fn spawn_monitor(db: RocksDB, events: Data<Events>, interval: Duration) -> Arc<StallCounters> {
+    let counters = Arc::new(StallCounters::default());
+    let counted = counters.clone();
+    std::thread::spawn(move || {
+        let mut last = State::Normal;
+        loop {
+            std::thread::sleep(interval);
+            let state = current(&db);
+            if state == last {
+                continue;
+            }
+            match state {
+                State::Delayed => counted.delayed.fetch_add(1, Ordering::Relaxed),
+                State::Stopped => counted.stopped.fetch_add(1, Ordering::Relaxed),
+                State::Normal => 0,
+            };
+            let l0_files = db.property_int("rocksdb.num-files-at-level0");
+            let pending_compaction_bytes =
+                db.property_int("rocksdb.estimate-pending-compaction-bytes");
+            if state == State::Normal {
+                log::info!("Writes are no longer {}", last.name());
+            } else {
+                log::warn!(
+                    "Writes are {} ({:?} L0 files, {:?} bytes pending compaction)",
+                    state.name(),
+                    l0_files,
+                    pending_compaction_bytes
+                );
+            }
+            events.emit(
+                "write_stall",
+                json!({
+                    "state": state.name(),
+                    "previous": last.name(),
+                    "l0_files": l0_files,
+                    "pending_compaction_bytes": pending_compaction_bytes,
+                    "delayed_total": counted.delayed.load(Ordering::Relaxed),
+                    "stopped_total": counted.stopped.load(Ordering::Relaxed),
+                }),
+            );
+            last = state;
+        }
+    });
+    counters
+}
@@ -0,0 +1,286 @@
+use crate::export::to_pointer;
+use crate::kv::{self, KVStore, RocksDB};
+use crate::validation::Validation;
+
+use actix_web::HttpResponse;
+use serde_json::{json, Value};
+use std::sync::Mutex;
+
+const PREFIX: &str = "_unique/";
+
+/// A field that must hold a different value in every key under `prefix`.
+struct Constraint {
+    /// As configured, `[prefix:]path`
+    name: String,
+    prefix: String,
+    path: String,
+    pointer: String,
+}
+
+/// Enforces unique value fields on key writes, with an index of
+/// `_unique/<constraint>/<value>` entries pointing at the key holding each
+/// value. Entries are removed when their key is rewritten or deleted through
+/// here. Writes that bypass it, such as set operations, can still leave
+/// entries behind, so they're also checked against the key when another one
+/// claims the value.
+pub struct UniqueFields {
+    constraints: Vec<Constraint>,
+    validation: Validation,
+    /// Serializes checking the index with writing to it
+    lock: Mutex<()>,
+}
+
+impl UniqueFields {
+    /// Reads the comma separated `[prefix:]path` list in `UNIQUE_FIELDS`. Paths
+    /// are dot delimited, as for export columns.
+    pub fn from_env(validation: &Validation) -> Option<Self> {
+        Self::parse(
+            &std::env::var("UNIQUE_FIELDS").unwrap_or_default(),
+            validation,
+        )
+    }
+
+    fn parse(fields: &str, validation: &Validation) -> Option<Self> {
+        let constraints: Vec<Constraint> = fields
+            .split(',')
+            .map(str::trim)
+            .filter(|c| !c.is_empty())
+            .map(|c| {
+                let (prefix, path) = c.split_once(':').unwrap_or(("", c));
+                Constraint {
+                    name: c.to_string(),
+                    prefix: prefix.to_string(),
+                    path: path.to_string(),
+                    pointer: to_pointer(path),
+                }
+            })
+            .collect();
+        if constraints.is_empty() {
+            return None;
+        }
+        Some(UniqueFields {
+            constraints,
+            validation: validation.clone(),
+            lock: Mutex::new(()),
+        })
+    }
+
+    fn applying_to<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a Constraint> + 'a {
+        self.constraints
+            .iter()
+            .filter(move |c| !self.validation.is_reserved(key) && key.starts_with(&c.prefix))
+    }
+
+    pub fn covers(&self, key: &str) -> bool {
+        self.applying_to(key).next().is_some()
+    }
+
+    /// Whether `owner` still holds `value` in the constrained field.
+    fn holds(db: &RocksDB, owner: &str, constraint: &Constraint, value: &Value) -> bool {
+        db.find(owner)
+            .and_then(|v| serde_json::from_str::<Value>(&v).ok())
+            .map_or(false, |doc| doc.pointer(&constraint.pointer) == Some(value))
+    }
+
+    fn index_key(constraint: &Constraint, field: &Value) -> String {
+        format!("{PREFIX}{}/{}", constraint.name, field)
+    }
+
+    /// The index entries pointing at `key` for the value it currently holds.
+    fn entries_of(&self, db: &RocksDB, key: &str) -> Vec<String> {
+        let Some(doc) = db
+            .find(key)
+            .and_then(|v| serde_json::from_str::<Value>(&v).ok())
+        else {
+            return Vec::new();
+        };
+        self.applying_to(key)
+            .filter_map(|constraint| match doc.pointer(&constraint.pointer) {
+                None | Some(Value::Null) => None,
+                Some(field) => Some(Self::index_key(constraint, field)),
+            })
+            .filter(|index_key| db.find(index_key).as_deref() == Some(key))
+            .collect()
+    }
+
+    /// Stores `value` at `key` together with its index entries, unless another
+    /// key already holds one of its unique field values. Entries for values
+    /// the key gives up are removed.
+    pub fn save(&self, db: &RocksDB, key: &str, value: &Value) -> Result<(), HttpResponse> {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let mut entries = Vec::new();
+        for constraint in self.applying_to(key) {
+            let field = match value.pointer(&constraint.pointer) {
+                None | Some(Value::Null) => continue,
+                Some(field) => field,
+            };
+            let index_key = Self::index_key(constraint, field);
+            if let Some(owner) = db.find(&index_key) {
+                if owner != key && Self::holds(db, &owner, constraint, field) {
+                    return Err(HttpResponse::Conflict()
+                        .content_type("application/json")
+                        .body(
+                            json!({
+                                "status": 409,
+                                "msg": format!("{} must be unique", constraint.path),
+                                "field": constraint.path,
+                                "key": owner,
+                            })
+                            .to_string(),
+                        ));
+                }
+            }
+            entries.push(index_key);
+        }
+
+        let stale: Vec<String> = self
+            .entries_of(db, key)
+            .into_iter()
+            .filter(|index_key| !entries.contains(index_key))
+            .collect();
+        let value = value.to_string();
+        let entries = std::iter::once((key, Some(value.as_str())))
+            .chain(
+                entries
+                    .iter()
+                    .map(|index_key| (index_key.as_str(), Some(key))),
+            )
+            .chain(stale.iter().map(|index_key| (index_key.as_str(), None)));
+        db.apply_batch(entries).map_err(|e| {
+            log::error!("Error saving {}: {}", key, e);
+            HttpResponse::InternalServerError()
+                .content_type("application/json")
+                .finish()
+        })
+    }
+
+    /// Deletes `key` together with its index entries. Returns the entries.
+    fn remove(&self, db: &RocksDB, key: &str) -> Result<Vec<String>, kv::Error> {
+        let entries = self.entries_of(db, key);
+        let deletes = std::iter::once(key).chain(entries.iter().map(String::as_str));
+        db.apply_batch(deletes.map(|k| (k, None)))?;
+        Ok(entries)
+    }
+
+    pub fn delete(&self, db: &RocksDB, key: &str) -> Result<(), kv::Error> {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        self.remove(db, key).map(|_| ())
+    }
+
+    /// Like `RocksDB::purge`, compacting the index entries away along with
+    /// the key. Returns whether the key existed.
+    pub fn purge(&self, db: &RocksDB, key: &str) -> Result<bool, kv::Error> {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let existed = db.find_raw(key).is_some();
+        for k in std::iter::once(key.to_string()).chain(self.remove(db, key)?) {
+            db.compact_away(&k);
+        }
+        Ok(existed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv::TempDB;
+    use actix_web::{body::to_bytes, http::StatusCode};
+
+    fn unique(fields: &str) -> UniqueFields {
+        UniqueFields::parse(fields, &Validation::from_env()).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn rejects_a_second_key_with_the_same_value() {
+        let tmp = TempDB::open();
+        let unique = unique("user:email,user:profile.handle");
+        assert!(unique
+            .save(&tmp.db, "user1", &json!({ "email": "a@x" }))
+            .is_ok());
+        // Rewriting the key that holds the value is fine
+        assert!(unique
+            .save(&tmp.db, "user1", &json!({ "email": "a@x" }))
+            .is_ok());
+
+        let res = unique
+            .save(&tmp.db, "user2", &json!({ "email": "a@x" }))
+            .unwrap_err();
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        let body: Value =
+            serde_json::from_slice(&to_bytes(res.into_body()).await.unwrap()).unwrap();
+        assert_eq!(body["field"], "email");
+        assert_eq!(body["key"], "user1");
+        assert!(tmp.db.find("user2").is_none());
+
+        let nested = json!({ "email": "b@x", "profile": { "handle": "a@x" } });
+        assert!(unique.save(&tmp.db, "user2", &nested).is_ok());
+    }
+
+    #[test]
+    fn frees_values_their_key_gave_up() {
+        let tmp = TempDB::open();
+        let unique = unique("email");
+        assert!(unique.save(&tmp.db, "a", &json!({ "email": "x" })).is_ok());
+        assert!(unique.save(&tmp.db, "a", &json!({ "email": "y" })).is_ok());
+        assert!(unique.save(&tmp.db, "b", &json!({ "email": "x" })).is_ok());
+    }
+
+    #[test]
+    fn only_applies_within_its_prefix() {
+        let tmp = TempDB::open();
+        let unique = unique("user:email");
+        for key in ["user1", "team1", "team2"] {
+            assert!(unique
+                .save(&tmp.db, key, &json!({ "email": "a@x" }))
+                .is_ok());
+        }
+        assert!(unique
+            .save(&tmp.db, "user2", &json!({ "email": null }))
+            .is_ok());
+        assert!(unique
+            .save(&tmp.db, "user3", &json!({ "name": "a@x" }))
+            .is_ok());
+    }
+
+    #[test]
+    fn drops_entries_for_values_their_key_gave_up() {
+        let tmp = TempDB::open();
+        let unique = unique("email");
+        assert!(unique.save(&tmp.db, "a", &json!({ "email": "x" })).is_ok());
+        assert!(unique.save(&tmp.db, "a", &json!({ "email": "y" })).is_ok());
+        assert!(tmp.db.find(r#"_unique/email/"x""#).is_none());
+        assert_eq!(tmp.db.find(r#"_unique/email/"y""#).as_deref(), Some("a"));
+
+        assert!(unique.save(&tmp.db, "a", &json!({ "name": "y" })).is_ok());
+        assert!(tmp.db.find(r#"_unique/email/"y""#).is_none());
+    }
+
+    #[test]
+    fn deletes_and_purges_remove_entries() {
+        let tmp = TempDB::open();
+        let unique = unique("email");
+        assert!(unique.save(&tmp.db, "a", &json!({ "email": "x" })).is_ok());
+        unique.delete(&tmp.db, "a").unwrap();
+        assert!(tmp.db.find("a").is_none());
+        assert!(tmp.db.find(r#"_unique/email/"x""#).is_none());
+
+        assert!(unique.save(&tmp.db, "b", &json!({ "email": "x" })).is_ok());
+        assert!(unique.purge(&tmp.db, "b").unwrap());
+        assert!(tmp.db.find("b").is_none());
+        assert!(tmp.db.find(r#"_unique/email/"x""#).is_none());
+        assert!(!unique.purge(&tmp.db, "b").unwrap());
+    }
+
+    #[test]
+    fn keeps_entries_another_key_claimed() {
+        let tmp = TempDB::open();
+        let unique = unique("email");
+        assert!(unique.save(&tmp.db, "a", &json!({ "email": "x" })).is_ok());
+        // Written around the index, so the entry for x still points at a
+        tmp.db.save("a", r#"{"email":"y"}"#);
+        assert!(unique.save(&tmp.db, "b", &json!({ "email": "x" })).is_ok());
+        tmp.db.save("a", r#"{"email":"x"}"#);
+
+        unique.delete(&tmp.db, "a").unwrap();
+        assert_eq!(tmp.db.find(r#"_unique/email/"x""#).as_deref(), Some("b"));
+    }
+}
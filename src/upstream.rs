@@ -0,0 +1,198 @@
+use crate::clock::now;
+use crate::kv::{KVStore, RocksDB};
+use crate::validation::Validation;
+
+use actix_web::{http::header, HttpResponse};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+const PREFIX: &str = "_cache/";
+
+thread_local! {
+    // The HTTP client isn't Send, so each worker keeps its own
+    static CLIENT: awc::Client = awc::Client::default();
+}
+
+/// Marks a key as a copy of the upstream value, stored under `_cache/<key>`.
+#[derive(Serialize, Deserialize)]
+struct Cached {
+    /// When to fetch the value again, `None` meaning never
+    expires_at: Option<u64>,
+}
+
+fn cache_key(key: &str) -> String {
+    format!("{PREFIX}{key}")
+}
+
+/// Serves keys missing here from another smol-kv instance, keeping a copy
+/// that is fetched again once it is older than the TTL. Keys written here
+/// that were never fetched are left alone.
+pub struct ReadThrough {
+    url: String,
+    /// Milliseconds, 0 keeps copies until they're deleted
+    ttl: u64,
+    prefixes: Vec<String>,
+    validation: Validation,
+}
+
+impl ReadThrough {
+    /// Enabled by `READ_THROUGH_URL`. `READ_THROUGH_PREFIXES` limits it to keys
+    /// starting with any of the comma separated prefixes.
+    pub fn from_env(validation: &Validation) -> Option<Self> {
+        let url = std::env::var("READ_THROUGH_URL").ok()?;
+        let ttl = std::env::var("READ_THROUGH_TTL_MS")
+            .unwrap_or("60000".to_string())
+            .parse::<u64>()
+            .unwrap();
+        let prefixes = std::env::var("READ_THROUGH_PREFIXES")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|p| !p.is_empty())
+            .map(String::from)
+            .collect();
+        Some(ReadThrough {
+            url: url.trim_end_matches('/').to_string(),
+            ttl,
+            prefixes,
+            validation: validation.clone(),
+        })
+    }
+
+    fn covers(&self, key: &str) -> bool {
+        !self.validation.is_reserved(key)
+            && (self.prefixes.is_empty() || self.prefixes.iter().any(|p| key.starts_with(p)))
+    }
+
+    /// The upstream value of `key`, `None` if it doesn't exist there.
+    async fn fetch(&self, key: &str) -> Result<Option<Value>, String> {
+        let client = CLIENT.with(Clone::clone);
+        let mut res = client
+            .get(format!(
+                "{}/api/{}",
+                self.url,
+                utf8_percent_encode(key, NON_ALPHANUMERIC)
+            ))
+            .insert_header((header::ACCEPT, "application/json"))
+            .send()
+            .await
+            .map_err(|e| format!("{key}: {e}"))?;
+        if res.status().as_u16() == 404 {
+            return Ok(None);
+        }
+        if !res.status().is_success() {
+            return Err(format!("{key}: upstream responded with {}", res.status()));
+        }
+        let body = res
+            .body()
+            .limit(self.validation.max_value_size())
+            .await
+            .map_err(|e| format!("{key}: {e}"))?;
+        serde_json::from_slice(&body)
+            .map(Some)
+            .map_err(|e| format!("{key}: upstream value is not JSON: {e}"))
+    }
+
+    /// Takes what was found locally for `key` and returns what to serve,
+    /// going upstream if it is missing or an expired copy. Stale copies are
+    /// served when upstream can't be reached.
+    pub async fn read(
+        &self,
+        db: &RocksDB,
+        key: &str,
+        local: Option<Vec<u8>>,
+    ) -> Result<Option<Vec<u8>>, HttpResponse> {
+        if !self.covers(key) {
+            return Ok(local);
+        }
+        let cached = db
+            .find(&cache_key(key))
+            .and_then(|v| serde_json::from_str::<Cached>(&v).ok());
+        let expired = cached
+            .as_ref()
+            .map_or(false, |c| c.expires_at.map_or(false, |at| at <= now()));
+        if local.is_some() && !expired {
+            return Ok(local);
+        }
+
+        match self.fetch(key).await {
+            Ok(Some(value)) => {
+                let value = value.to_string();
+                let marker = json!(Cached {
+                    expires_at: (self.ttl > 0).then(|| now() + self.ttl),
+                })
+                .to_string();
+                let cache_key = cache_key(key);
+                if let Err(e) =
+                    db.write_batch([(key, value.as_str()), (&cache_key, &marker)].into_iter())
+                {
+                    log::error!("Error caching {}: {}", key, e);
+                }
+                Ok(Some(value.into_bytes()))
+            }
+            Ok(None) => {
+                // Gone upstream, so drop the copy too
+                if cached.is_some() {
+                    db.delete(key);
+                    db.delete(&cache_key(key));
+                }
+                Ok(None)
+            }
+            Err(e) if local.is_some() => {
+                log::warn!("Serving stale copy, read-through failed: {}", e);
+                Ok(local)
+            }
+            Err(e) => {
+                log::warn!("Read-through failed: {}", e);
+                Err(HttpResponse::BadGateway()
+                    .content_type("application/json")
+                    .body(json!({ "status": 502, "msg": e }).to_string()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv::TempDB;
+    use crate::kv_handler::{self, ReadParams};
+    use actix_web::{
+        http::StatusCode,
+        web::{Data, Path, Query},
+    };
+
+    #[actix_web::test]
+    async fn heads_go_upstream_like_gets() {
+        let tmp = TempDB::open();
+        let db = Data::new(tmp.db.clone());
+        // Nothing listens on port 1, so going upstream fails
+        let read_through = Data::new(ReadThrough {
+            url: "http://127.0.0.1:1".to_string(),
+            ttl: 0,
+            prefixes: Vec::new(),
+            validation: Validation::from_env(),
+        });
+        let params = || Query::<ReadParams>::from_query("").unwrap();
+
+        let res = kv_handler::head(
+            Path::from("a".to_string()),
+            db.clone(),
+            params(),
+            Some(read_through.clone()),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+
+        tmp.db.save("a", "1");
+        let res = kv_handler::head(
+            Path::from("a".to_string()),
+            db,
+            params(),
+            Some(read_through),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,178 @@
+use actix_web::{dev::Decompress, http::header, web::Payload, HttpRequest, HttpResponse};
+use bytes::{Bytes, BytesMut};
+use serde_json::json;
+use tokio_stream::StreamExt;
+
+/// Where locks, leases, sequences and the other internal state live. Always
+/// reserved, whatever `KEY_RESERVED_PREFIXES` is set to.
+const INTERNAL_PREFIXES: &[&str] = &[
+    "_alias/", "_cache/", "_lease/", "_leased/", "_lock/", "_migrate", "_seq/", "_unique/",
+];
+
+/// Whether `key` holds internal state rather than user data.
+pub fn is_internal(key: &str) -> bool {
+    INTERNAL_PREFIXES.iter().any(|p| key.starts_with(p))
+}
+
+#[derive(Clone)]
+pub struct Validation {
+    max_value_size: usize,
+    max_key_length: usize,
+    key_charset: String,
+    reserved_prefixes: Vec<String>,
+}
+
+/// Request body limits per class of route, enforced while the body is read.
+#[derive(Clone)]
+pub struct PayloadLimits {
+    /// Routes that carry a value: key writes, queue and array operations
+    pub value: usize,
+    /// Small JSON bodies for leases and locks
+    pub control: usize,
+}
+
+impl PayloadLimits {
+    pub fn from_env() -> Self {
+        let value = std::env::var("PAYLOAD_LIMIT_VALUE")
+            .unwrap_or((1024 * 1024 * 50).to_string())
+            .parse::<usize>()
+            .unwrap();
+        let control = std::env::var("PAYLOAD_LIMIT_CONTROL")
+            .unwrap_or((1024 * 64).to_string())
+            .parse::<usize>()
+            .unwrap();
+
+        PayloadLimits { value, control }
+    }
+}
+
+impl Validation {
+    pub fn from_env() -> Self {
+        let max_value_size = std::env::var("MAX_VALUE_SIZE")
+            .unwrap_or((1024 * 1024 * 50).to_string())
+            .parse::<usize>()
+            .unwrap();
+        let max_key_length = std::env::var("KEY_MAX_LENGTH")
+            .unwrap_or("512".to_string())
+            .parse::<usize>()
+            .unwrap();
+        // Allowed on top of ASCII letters and digits
+        let key_charset = std::env::var("KEY_CHARSET").unwrap_or("-_.:@~".to_string());
+        let reserved_prefixes = std::env::var("KEY_RESERVED_PREFIXES")
+            .unwrap_or("_".to_string())
+            .split(',')
+            .map(str::trim)
+            .filter(|p| !p.is_empty())
+            .map(String::from)
+            .collect();
+
+        Validation {
+            max_value_size,
+            max_key_length,
+            key_charset,
+            reserved_prefixes,
+        }
+    }
+
+    pub fn too_large(&self) -> HttpResponse {
+        HttpResponse::PayloadTooLarge()
+            .content_type("application/json")
+            .body(
+                json!({
+                    "status": 413,
+                    "msg": format!("Value exceeds the maximum size of {} bytes", self.max_value_size)
+                })
+                .to_string(),
+            )
+    }
+
+    pub fn max_value_size(&self) -> usize {
+        self.max_value_size
+    }
+
+    pub fn check_value(&self, value: &[u8]) -> Result<(), HttpResponse> {
+        if value.len() > self.max_value_size {
+            return Err(self.too_large());
+        }
+        Ok(())
+    }
+
+    /// Reads a value from the request body, giving up as soon as the declared
+    /// or received length goes over the maximum value size instead of
+    /// buffering the whole body first. Compressed bodies are decompressed,
+    /// and the limit applies to the decompressed size.
+    pub async fn read_value(
+        &self,
+        req: &HttpRequest,
+        payload: Payload,
+    ) -> Result<Bytes, HttpResponse> {
+        // The declared length of a compressed body says little about its size
+        let encoded = req.headers().contains_key(header::CONTENT_ENCODING);
+        let declared = req
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|hv| hv.to_str().ok())
+            .and_then(|hv| hv.parse::<usize>().ok())
+            .filter(|_| !encoded);
+        if declared.map_or(false, |len| len > self.max_value_size) {
+            return Err(self.too_large());
+        }
+
+        let mut payload = Decompress::from_headers(payload, req.headers());
+        let mut body = BytesMut::with_capacity(declared.unwrap_or(0));
+        while let Some(chunk) = payload.next().await {
+            let chunk = chunk.map_err(|e| {
+                HttpResponse::BadRequest()
+                    .content_type("application/json")
+                    .body(json!({ "status": 400, "msg": e.to_string() }).to_string())
+            })?;
+            if body.len() + chunk.len() > self.max_value_size {
+                return Err(self.too_large());
+            }
+            body.extend_from_slice(&chunk);
+        }
+        Ok(body.freeze())
+    }
+
+    /// Whether `key` holds internal state or lives under one of the
+    /// configured reserved prefixes.
+    pub fn is_reserved(&self, key: &str) -> bool {
+        is_internal(key) || self.reserved_prefixes.iter().any(|p| key.starts_with(p))
+    }
+
+    pub fn check_key(&self, key: &str) -> Result<(), HttpResponse> {
+        let mut details = Vec::new();
+
+        if key.is_empty() {
+            details.push("key must not be empty".to_string());
+        }
+        if key.len() > self.max_key_length {
+            details.push(format!(
+                "key exceeds the maximum length of {} bytes",
+                self.max_key_length
+            ));
+        }
+        let invalid: String = key
+            .chars()
+            .filter(|c| !c.is_ascii_alphanumeric() && !self.key_charset.contains(*c))
+            .collect();
+        if !invalid.is_empty() {
+            details.push(format!("key contains disallowed characters: {:?}", invalid));
+        }
+        let reserved = INTERNAL_PREFIXES
+            .iter()
+            .copied()
+            .chain(self.reserved_prefixes.iter().map(String::as_str))
+            .find(|p| key.starts_with(p));
+        if let Some(prefix) = reserved {
+            details.push(format!("key uses the reserved prefix {:?}", prefix));
+        }
+
+        if details.is_empty() {
+            return Ok(());
+        }
+        Err(HttpResponse::BadRequest()
+            .content_type("application/json")
+            .body(json!({ "status": 400, "msg": "Invalid key", "details": details }).to_string()))
+    }
+}
@@ -0,0 +1,98 @@
+use crate::events::Events;
+
+use actix_web::web::Data;
+use serde_json::json;
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// Watches free space on the volume holding the database and flags it as low
+/// below a threshold, so writes can be refused before RocksDB runs out of
+/// room mid-write.
+pub struct DiskWatchdog {
+    low: AtomicBool,
+}
+
+/// Bytes available to unprivileged users on the filesystem holding `path`.
+#[cfg(unix)]
+fn free_bytes(path: &str) -> io::Result<u64> {
+    let path =
+        std::ffi::CString::new(path).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+fn free_bytes(_: &str) -> io::Result<u64> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "checking free space is only supported on unix",
+    ))
+}
+
+impl DiskWatchdog {
+    /// Starts watching if `DISK_MIN_FREE_BYTES` is set above 0, checking
+    /// every `DISK_CHECK_INTERVAL_MS`. Crossing the threshold either way is
+    /// published on `events`.
+    pub fn from_env(db_path: &str, events: Data<Events>) -> Option<Arc<Self>> {
+        let min_free = std::env::var("DISK_MIN_FREE_BYTES")
+            .unwrap_or("0".to_string())
+            .parse::<u64>()
+            .unwrap();
+        let interval = std::env::var("DISK_CHECK_INTERVAL_MS")
+            .unwrap_or("5000".to_string())
+            .parse::<u64>()
+            .unwrap();
+        if min_free == 0 {
+            return None;
+        }
+        if cfg!(not(unix)) {
+            log::warn!("DISK_MIN_FREE_BYTES is only supported on unix, not watching disk space");
+            return None;
+        }
+
+        let watchdog = Arc::new(DiskWatchdog {
+            low: AtomicBool::new(false),
+        });
+        let watcher = watchdog.clone();
+        let path = db_path.to_string();
+        std::thread::spawn(move || loop {
+            match free_bytes(&path) {
+                Ok(free) => watcher.check(free, min_free, &events),
+                Err(e) => log::error!("Error checking free space on {}: {}", path, e),
+            }
+            std::thread::sleep(Duration::from_millis(interval));
+        });
+        Some(watchdog)
+    }
+
+    fn check(&self, free: u64, min_free: u64, events: &Events) {
+        let low = free < min_free;
+        if self.low.swap(low, Ordering::Relaxed) == low {
+            return;
+        }
+        let kind = if low {
+            log::warn!("Only {free} bytes free, below {min_free}; refusing writes");
+            "disk_space_low"
+        } else {
+            log::info!("{free} bytes free again; accepting writes");
+            "disk_space_recovered"
+        };
+        events.emit(
+            kind,
+            json!({ "free_bytes": free, "min_free_bytes": min_free }),
+        );
+    }
+
+    pub fn is_low(&self) -> bool {
+        self.low.load(Ordering::Relaxed)
+    }
+}